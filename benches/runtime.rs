@@ -0,0 +1,126 @@
+//! Measures the per-call dispatch overhead of the old strategy (one OS thread and one Tokio
+//! runtime per call) against the shared runtime, for the `_sync` methods, which block on it, and
+//! for async calls such as `rows:next(cb)`, which are spawned on it. Every case runs the same
+//! statement on the same kind of connection.
+//!
+//! Run with `cargo bench --bench runtime`.
+
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+// The crate links against Lua, which Neovim provides at runtime.
+use luajit2_sys as _;
+
+use sql_lua::conn::ParamsList;
+use sql_lua::db::LuaDatabase;
+use sql_lua::pool::PoolConfig;
+
+const CALLS: u32 = 2_000;
+
+const SQL: &str = "SELECT 1";
+
+fn database() -> libsql::Database {
+    sql_lua::runtime::block_on(async {
+        libsql::Builder::new_local(":memory:")
+            .build()
+            .await
+            .map_err(mlua::Error::external)
+    })
+    .unwrap()
+}
+
+fn per_call_runtime() -> Duration {
+    let conn = database().connect().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let start = Instant::now();
+    for _ in 0..CALLS {
+        let tx = tx.clone();
+        let conn = conn.clone();
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            tx.send(rt.block_on(conn.query(SQL, ())).is_ok()).unwrap();
+        });
+        // The old `_sync` variants waited for each call before returning.
+        rx.recv().unwrap();
+    }
+    start.elapsed()
+}
+
+fn shared_runtime() -> Duration {
+    let conn = LuaDatabase::new(database(), None, PoolConfig::default())
+        .connect_sync()
+        .unwrap();
+    let start = Instant::now();
+    for _ in 0..CALLS {
+        conn.query_sync((
+            SQL.to_string(),
+            ParamsList::from(libsql::params::Params::None),
+        ))
+        .unwrap();
+    }
+    start.elapsed()
+}
+
+async fn next_row(conn: &libsql::Connection) -> libsql::Result<Option<libsql::Row>> {
+    conn.query(SQL, ()).await?.next().await
+}
+
+/// Async calls the old way: each on a thread of its own, with the result sent back to the Lua
+/// thread.
+fn per_call_runtime_async() -> Duration {
+    let conn = database().connect().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let start = Instant::now();
+    for _ in 0..CALLS {
+        let tx = tx.clone();
+        let conn = conn.clone();
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            tx.send(rt.block_on(next_row(&conn)).is_ok()).unwrap();
+        });
+        // Waits for the callback, so calls don't pile up.
+        rx.recv().unwrap();
+    }
+    start.elapsed()
+}
+
+/// Async calls as `rows:next(cb)` dispatches them, up to the libuv wakeup that runs the callback:
+/// spawned on the shared runtime, with the result sent back to the Lua thread. The wakeup costs
+/// the same either way and needs a running Neovim, so it is left out.
+fn shared_runtime_async() -> Duration {
+    let conn = database().connect().unwrap();
+    let rt = sql_lua::runtime::get().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let start = Instant::now();
+    for _ in 0..CALLS {
+        let tx = tx.clone();
+        let conn = conn.clone();
+        rt.spawn(async move {
+            tx.send(next_row(&conn).await.is_ok()).unwrap();
+        });
+        rx.recv().unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{name:<20} {CALLS} calls in {elapsed:>10.2?} ({:>8.2?} per call)",
+        elapsed / CALLS
+    );
+}
+
+fn compare(kind: &str, old: Duration, new: Duration) {
+    println!("{kind}:");
+    report("per-call runtime", old);
+    report("shared runtime", new);
+    println!(
+        "speedup: {:.1}x",
+        old.as_secs_f64() / new.as_secs_f64().max(f64::EPSILON)
+    );
+}
+
+fn main() {
+    compare("sync", per_call_runtime(), shared_runtime());
+    compare("async", per_call_runtime_async(), shared_runtime_async());
+}
@@ -1,6 +1,7 @@
 use proc_macro::TokenStream;
 use proc_macro2::Span;
-use quote::quote;
+use quote::{format_ident, quote};
+use syn::ext::IdentExt;
 use syn::{parse_macro_input, parse_quote, DeriveInput, ImplItemFn};
 
 #[proc_macro_derive(FromLuaSerde)]
@@ -43,24 +44,121 @@ impl syn::visit_mut::VisitMut for ReplaceSelfVisitor {
     }
 }
 
+/// Turns an `async fn` returning `mlua::Result<T>` into a method for `add_async_method`.
+///
+/// The last argument must be bound as `cb: Option<OwnedFunction>`. The body is spawned on the
+/// shared runtime by `crate::task::spawn`, and the method returns a `LuaFuture` for its result.
+///
+/// Options, as in `#[luv_async(sync, timeout = expr)]`:
+/// - `sync` also emits `<name>_sync`, which takes the same arguments minus `cb` and blocks on
+///   the same body.
+/// - `timeout = expr` limits how long the call may run, sync or not. `expr` is an
+///   `Option<Duration>` evaluated before the body is spawned, so it may use `self`.
 #[proc_macro_attribute]
-pub fn luv_async(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn luv_async(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let options = parse_macro_input!(attr as MethodOptions);
+    let item = parse_macro_input!(item as ImplItemFn);
+
+    expand_async(&options, item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Generates `add_lua_methods`, which registers every method of the impl block marked with
+/// `#[lua_method]`. Call it from `UserData::add_methods`.
+///
+/// `#[lua_method]` registers a plain method with `add_method`. `#[lua_method(async)]` expands the
+/// method like `#[luv_async]` and registers it with `add_async_method`, and also takes the `sync`
+/// and `timeout = expr` options, registering `<name>_sync` along with it.
+#[proc_macro_attribute]
+pub fn lua_methods(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as syn::ItemImpl);
+
+    expand_lua_methods(item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Marker read by `#[lua_methods]`. On its own it leaves the method untouched.
+#[proc_macro_attribute]
+pub fn lua_method(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    item
+}
+
+/// Options of `#[luv_async]` and `#[lua_method]`.
+struct MethodOptions {
+    is_async: bool,
+    sync: bool,
+    timeout: Option<syn::Expr>,
+}
+
+impl syn::parse::Parse for MethodOptions {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let mut options = MethodOptions {
+            is_async: false,
+            sync: false,
+            timeout: None,
+        };
+
+        while !input.is_empty() {
+            if input.parse::<Option<syn::Token![async]>>()?.is_some() {
+                options.is_async = true;
+            } else {
+                let name: syn::Ident = input.parse()?;
+                if name == "sync" {
+                    options.sync = true;
+                } else if name == "timeout" {
+                    input.parse::<syn::Token![=]>()?;
+                    options.timeout = Some(input.parse()?);
+                } else {
+                    return Err(syn::Error::new_spanned(
+                        name,
+                        "expected `async`, `sync` or `timeout = ...`",
+                    ));
+                }
+            }
+
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+fn expand_async(
+    options: &MethodOptions,
+    item: ImplItemFn,
+) -> syn::Result<proc_macro2::TokenStream> {
     let ImplItemFn {
+        attrs,
         vis,
         mut sig,
         mut block,
         ..
-    } = parse_macro_input!(item as syn::ImplItemFn);
+    } = item;
+
+    let timeout = match &options.timeout {
+        Some(timeout) => quote! { #timeout },
+        None => quote! { ::std::option::Option::None },
+    };
 
-    let return_type = &sig.output;
-    let output = match return_type.clone() {
-        syn::ReturnType::Type(_, t) => t,
-        syn::ReturnType::Default => panic!(),
+    let sync_variant = if options.sync {
+        sync_variant(&attrs, &vis, &sig, &block, &timeout)?
+    } else {
+        quote! {}
     };
-    sig.output = parse_quote! { -> ::mlua::Result<()> };
+
+    sig.output = parse_quote! { -> crate::future::Pending<'__lua> };
 
     sig.asyncness = None;
 
+    sig.generics.params.insert(0, parse_quote! { '__lua });
+    let lua_position = if sig.receiver().is_some() { 1 } else { 0 };
+    sig.inputs
+        .insert(lua_position, parse_quote! { __lua: &'__lua ::mlua::Lua });
+
     let self_clone = if sig.receiver().is_some() {
         let replacement_ident = syn::Ident::new("__self", Span::call_site());
 
@@ -70,41 +168,170 @@ pub fn luv_async(_attr: TokenStream, item: TokenStream) -> TokenStream {
         quote! {}
     };
 
-    quote! {
+    Ok(quote! {
+        #(#attrs)*
         #vis #sig {
-            let data = ::std::sync::Arc::new(::tokio::sync::Mutex::new(None));
-
-            let handle = ::nvim_oxi::libuv::AsyncHandle::new({
-                let data = ::std::sync::Arc::clone(&data);
-                move || {
-                    let Some(rv) = data.blocking_lock().take() else {
-                        return ::mlua::Result::Err(mlua::Error::RuntimeError(
-                            "data not set".to_string(),
-                        ));
-                    };
-                    cb.call(rv)
-                }
-            })
-            .into_lua_err()?;
-
-            ::std::thread::spawn({
-                #self_clone
-                move || {
-                    let rt = ::tokio::runtime::Runtime::new().into_lua_err()?;
-                    rt.block_on(async {
-                        let res: #output = async { #block }.await;
-
-                        data.lock()
-                            .await
-                            .replace(res);
-                        handle.send().into_lua_err()?;
-                        ::mlua::Result::Ok(())
-                    })
-                }
-            });
+            let __timeout = #timeout;
+            #self_clone
+            crate::task::spawn(__lua, cb, __timeout, async move #block)
+        }
+
+        #sync_variant
+    })
+}
+
+fn expand_lua_methods(mut item: syn::ItemImpl) -> syn::Result<proc_macro2::TokenStream> {
+    if let Some((_, path, _)) = &item.trait_ {
+        return Err(syn::Error::new_spanned(
+            path,
+            "`lua_methods` goes on an inherent impl block",
+        ));
+    }
+
+    let mut methods = Vec::new();
+    let mut registrations = Vec::new();
+
+    for impl_item in std::mem::take(&mut item.items) {
+        let syn::ImplItem::Fn(mut method) = impl_item else {
+            methods.push(quote! { #impl_item });
+            continue;
+        };
+        let Some(position) = method
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("lua_method"))
+        else {
+            methods.push(quote! { #method });
+            continue;
+        };
 
-            Ok(())
+        let options = match method.attrs.remove(position).meta {
+            syn::Meta::Path(_) => MethodOptions {
+                is_async: false,
+                sync: false,
+                timeout: None,
+            },
+            meta => meta.require_list()?.parse_args()?,
+        };
+        if method.sig.receiver().is_none() {
+            return Err(syn::Error::new_spanned(
+                &method.sig,
+                "`lua_method` needs a `self` receiver",
+            ));
         }
+
+        let ident = &method.sig.ident;
+        let name = ident.unraw().to_string();
+        if !options.is_async {
+            if options.sync || options.timeout.is_some() {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "`sync` and `timeout` only apply to `async` methods",
+                ));
+            }
+            registrations.push(quote! { methods.add_method(#name, Self::#ident.wrap()); });
+            methods.push(quote! { #method });
+            continue;
+        }
+
+        registrations.push(quote! { methods.add_async_method(#name, Self::#ident.wrap_async()); });
+        if options.sync {
+            let sync_ident = format_ident!("{}_sync", name);
+            let sync_name = sync_ident.to_string();
+            registrations
+                .push(quote! { methods.add_method(#sync_name, Self::#sync_ident.wrap()); });
+        }
+        methods.push(expand_async(&options, method)?);
     }
-    .into()
+
+    let syn::ItemImpl {
+        attrs,
+        generics,
+        self_ty,
+        ..
+    } = &item;
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        #(#attrs)*
+        impl #impl_generics #self_ty #where_clause {
+            #(#methods)*
+
+            /// Registers the methods marked with `#[lua_method]`.
+            pub(crate) fn add_lua_methods<'lua, M: ::mlua::UserDataMethods<'lua, Self>>(
+                methods: &mut M,
+            ) {
+                #(#registrations)*
+            }
+        }
+    })
+}
+
+/// Emits `<name>_sync`, which blocks the calling thread on `block` instead of returning a future,
+/// under the same timeout.
+fn sync_variant(
+    attrs: &[syn::Attribute],
+    vis: &syn::Visibility,
+    sig: &syn::Signature,
+    block: &syn::Block,
+    timeout: &proc_macro2::TokenStream,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let mut sig = sig.clone();
+    sig.ident = format_ident!("{}_sync", sig.ident);
+    sig.asyncness = None;
+    strip_callback(&mut sig)?;
+
+    let mut block = block.clone();
+    let self_clone = if sig.receiver().is_some() {
+        let replacement_ident = syn::Ident::new("__self", Span::call_site());
+
+        syn::visit_mut::visit_block_mut(&mut ReplaceSelfVisitor { replacement_ident }, &mut block);
+        quote! { let __self = self.clone(); }
+    } else {
+        quote! {}
+    };
+
+    Ok(quote! {
+        #(#attrs)*
+        #vis #sig {
+            let __timeout = #timeout;
+            #self_clone
+            crate::task::block_on(__timeout, async move #block)
+        }
+    })
+}
+
+/// Removes the trailing `cb` from a signature, whether it is an argument of its own or the last
+/// element of a destructured tuple.
+fn strip_callback(sig: &mut syn::Signature) -> syn::Result<()> {
+    let Some(syn::FnArg::Typed(arg)) = sig.inputs.last_mut() else {
+        return Err(syn::Error::new_spanned(
+            &sig.inputs,
+            "expected a `cb` argument",
+        ));
+    };
+
+    let remaining = match (&mut *arg.pat, &mut *arg.ty) {
+        (syn::Pat::Ident(_), _) => None,
+        (syn::Pat::Tuple(pat), syn::Type::Tuple(ty)) if pat.elems.len() == ty.elems.len() => {
+            pat.elems.pop();
+            pat.elems.pop_punct();
+            ty.elems.pop();
+            ty.elems.pop_punct();
+            match (pat.elems.len(), pat.elems.first(), ty.elems.first()) {
+                (0, ..) => None,
+                (1, Some(pat), Some(ty)) => Some(parse_quote! { #pat: #ty }),
+                _ => return Ok(()),
+            }
+        }
+        _ => return Err(syn::Error::new_spanned(arg, "expected a `cb` argument")),
+    };
+
+    sig.inputs.pop();
+    sig.inputs.pop_punct();
+    if let Some(arg) = remaining {
+        sig.inputs.push(arg);
+    }
+
+    Ok(())
 }
@@ -1,36 +1,294 @@
 ---@meta
 
+---Async methods take an optional trailing `cb(err, result)` callback and return a `libsql.Future`
+---for the result. Inside a coroutine, a call without a callback instead yields until the result is
+---ready and returns it, raising on error. The `@return` annotations describe that form.
+---
+---Errors reported by libsql reach callbacks and `future:catch` as `libsql.Error` objects, and
+---other errors as strings. A call that is cancelled fails with a `libsql.Error` of kind
+---"cancelled", and one that runs past its connection's timeout with one of kind "timeout". Use
+---`libsql.error(err)` to get the `libsql.Error` behind an error caught with `pcall`.
+
+---@alias libsql.AnyError string | libsql.Error
+
+---Positional parameters as a sequence, or named parameters keyed by name. The `:`, `@` and `$`
+---prefixes are interchangeable and may be left out: `id`, `:id` and `@id` all bind `:id`, `@id`
+---and `$id`. A name that matches no placeholder is an error, except on remote databases, where
+---names are sent to the server as given. Table values bind as JSON text, so they can be read back
+---with `json_extract`; empty tables bind as `{}`, and `vim.NIL` as `null`. Tables that mix a
+---sequence with other keys, NaN and infinities have no JSON form and are errors.
+---@alias libsql.Params any[] | table<string, any>
+
 ---@class libsql.Connection
 local Connection = {}
 
 ---@param query string
----@param parameters any[]
----@param cb fun(rows: libsql.Rows)
+---@param parameters libsql.Params?
+---@param cb? fun(err: libsql.AnyError?, rows: libsql.Rows?)
+---@return libsql.Rows rows
 function Connection:query(query, parameters, cb) end
 
 ---@param query string
----@param parameters any[]
----@param cb fun(affected: integer)
+---@param parameters libsql.Params?
+---@return libsql.Rows
+function Connection:query_sync(query, parameters) end
+
+---@param query string
+---@param parameters libsql.Params?
+---@param cb? fun(err: libsql.AnyError?, affected: integer?)
+---@return integer affected
 function Connection:execute(query, parameters, cb) end
 
+---@param query string
+---@param parameters libsql.Params?
+---@return integer
+function Connection:execute_sync(query, parameters) end
+
+---Runs a script of one or more `;`-separated statements, e.g. a migration file.
+---@param sql string
+---@param cb? fun(err: libsql.AnyError?)
+function Connection:execute_batch(sql, cb) end
+
+---@param sql string
+function Connection:execute_batch_sync(sql) end
+
+---@param query string
+---@param cb? fun(err: libsql.AnyError?, stmt: libsql.Statement?)
+---@return libsql.Statement stmt
+function Connection:prepare(query, cb) end
+
+---@param query string
+---@return libsql.Statement
+function Connection:prepare_sync(query) end
+
+---@alias libsql.TransactionBehavior "deferred" | "immediate" | "exclusive"
+
+---@param behavior libsql.TransactionBehavior? Defaults to "deferred".
+---@param cb? fun(err: libsql.AnyError?, tx: libsql.Transaction?)
+---@return libsql.Transaction tx
+function Connection:transaction(behavior, cb) end
+
+---@param behavior libsql.TransactionBehavior? Defaults to "deferred".
+---@return libsql.Transaction
+function Connection:transaction_sync(behavior) end
+
+---Rowid of the last row inserted through this connection.
+---
+---This and the other connection state getters fail if a call is still running on the connection.
+---@return integer
+function Connection:last_insert_rowid() end
+
+---Rows changed by the last `INSERT`, `UPDATE` or `DELETE`.
+---@return integer
+function Connection:changes() end
+
+---Whether the connection is outside of a transaction.
+---@return boolean
+function Connection:is_autocommit() end
+
+---Rows changed since the connection was opened. Unlike the other getters this runs a query.
+---@param cb? fun(err: libsql.AnyError?, changes: integer?)
+---@return integer changes
+function Connection:total_changes(cb) end
+
+---@return integer
+function Connection:total_changes_sync() end
+
+---Tables, views, indexes and triggers of the main database, with their columns and foreign keys.
+---SQLite's internal `sqlite_*` objects are left out.
+---@param cb? fun(err: libsql.AnyError?, schema: libsql.Schema?)
+---@return libsql.Schema schema
+function Connection:schema(cb) end
+
+---@return libsql.Schema
+function Connection:schema_sync() end
+
+---@class libsql.Schema
+---@field tables libsql.SchemaTable[]
+---@field views libsql.SchemaView[]
+---@field indexes libsql.SchemaIndex[]
+---@field triggers libsql.SchemaTrigger[]
+
+---@class libsql.SchemaTable
+---@field name string
+---The `CREATE TABLE` statement.
+---@field sql string?
+---@field columns libsql.SchemaColumn[]
+---@field foreign_keys libsql.SchemaForeignKey[]
+
+---@class libsql.SchemaView
+---@field name string
+---@field sql string?
+---@field columns libsql.SchemaColumn[]
+---Why the columns could not be read, e.g. for a view of a table that has been dropped.
+---@field error string?
+
+---@class libsql.SchemaIndex
+---@field name string
+---@field table string
+---@field sql string?
+---@field unique boolean
+---Indexed columns in order. Expressions have no name and are left out.
+---@field columns string[]
+
+---@class libsql.SchemaTrigger
+---@field name string
+---@field table string
+---@field sql string?
+
+---@class libsql.SchemaColumn
+---@field name string
+---Declared type, as written in the `CREATE` statement.
+---@field type string?
+---@field notnull boolean
+---Default value, as SQL text.
+---@field default string?
+---Position in the primary key, starting at 1, or 0 if the column isn't part of it.
+---@field pk integer
+
+---@class libsql.SchemaForeignKey
+---The referenced table.
+---@field table string
+---@field from string[]
+---Referenced columns, nil where the key refers to the primary key of `table`.
+---@field to (string?)[]
+---@field on_update string
+---@field on_delete string
+
+---Sets the timeout of calls on this connection and the statements and transactions created from
+---it, `_sync` calls included.
+---@param timeout_ms integer? nil removes the timeout.
+function Connection:set_timeout(timeout_ms) end
+
+---Returns a handle to the same connection with its own timeout, for a single call:
+---`conn:with_timeout(500):query(sql)`.
+---@param timeout_ms integer? nil removes the timeout.
+---@return libsql.Connection
+function Connection:with_timeout(timeout_ms) end
+
+---Returns a handle to the same connection whose `query` results decode the given columns, e.g.
+---`conn:with_decode({ settings = "json" }):query(sql)` returns `row.settings` as a table. Queries
+---through statements and transactions created from it decode them too.
+---@param columns table<string, libsql.Format> Format of each column to decode, by name.
+---@param json_null libsql.NullMode?
+---@return libsql.Connection
+function Connection:with_decode(columns, json_null) end
+
+---Returns a handle to the same connection that converts values with `types`, on top of the
+---options it already has. Statements and transactions created from it use them too.
+---@param types libsql.TypeOptions
+---@return libsql.Connection
+function Connection:with_types(types) end
+
+---Closes the connection once the call running on it is done. Further calls on it fail, as do
+---calls on handles from `with_timeout`. Also runs when a `local conn <close>` goes out of scope.
+---
+---The handles to an in-memory database share the connection that holds its data, so only this
+---handle is closed and the others keep working.
+---@param cb? fun(err: libsql.AnyError?)
+function Connection:close(cb) end
+
+function Connection:close_sync() end
+
+---A transaction is rolled back automatically if it is garbage-collected before being committed.
+---@class libsql.Transaction
+local Transaction = {}
+
+---@param query string
+---@param parameters libsql.Params?
+---@param cb? fun(err: libsql.AnyError?, affected: integer?)
+---@return integer affected
+function Transaction:execute(query, parameters, cb) end
+
+---@param query string
+---@param parameters libsql.Params?
+---@return integer
+function Transaction:execute_sync(query, parameters) end
+
+---@param query string
+---@param parameters libsql.Params?
+---@param cb? fun(err: libsql.AnyError?, rows: libsql.Rows?)
+---@return libsql.Rows rows
+function Transaction:query(query, parameters, cb) end
+
+---@param query string
+---@param parameters libsql.Params?
+---@return libsql.Rows
+function Transaction:query_sync(query, parameters) end
+
+---@param cb? fun(err: libsql.AnyError?)
+function Transaction:commit(cb) end
+
+function Transaction:commit_sync() end
+
+---@param cb? fun(err: libsql.AnyError?)
+function Transaction:rollback(cb) end
+
+function Transaction:rollback_sync() end
+
+---`execute` and `query` reset the statement before binding, so it can be run again with new
+---parameters. Running it again ends the rows of an earlier `query`.
+---
+---`reset`, `parameter_count` and `columns` fail with "statement is busy with another call" while
+---an async `execute` or `query` is running on the statement.
+---@class libsql.Statement
+local Statement = {}
+
+---@param parameters libsql.Params?
+---@param cb? fun(err: libsql.AnyError?, affected: integer?)
+---@return integer affected
+function Statement:execute(parameters, cb) end
+
+---@param parameters libsql.Params?
+---@return integer
+function Statement:execute_sync(parameters) end
+
+---@param parameters libsql.Params?
+---@param cb? fun(err: libsql.AnyError?, rows: libsql.Rows?)
+---@return libsql.Rows rows
+function Statement:query(parameters, cb) end
+
+---@param parameters libsql.Params?
+---@return libsql.Rows
+function Statement:query_sync(parameters) end
+
+---Resets the statement so it can be executed again.
+function Statement:reset() end
+
+---@return integer
+function Statement:parameter_count() end
+
+---@class libsql.ColumnInfo
+---@field name string
+---@field origin_name string?
+---@field table_name string?
+---@field database_name string?
+---@field decl_type string?
+
+---@return libsql.ColumnInfo[]
+function Statement:columns() end
+
 ---@class libsql.Rows
 ---@overload fun():libsql.Row?
 local Rows = {}
 
----@param cb fun(row: libsql.Row?)
+---@param cb? fun(err: libsql.AnyError?, row: libsql.Row?)
+---@return libsql.Row? row
 function Rows:next(cb) end
 
 ---@return libsql.Row?
 function Rows:next_sync() end
 
----@param cb fun(columns: integer)
+---@param cb? fun(err: libsql.AnyError?, columns: integer?)
+---@return integer columns
 function Rows:column_count(cb) end
 
 ---@return integer
 function Rows:column_count_sync() end
 
 ---@param index integer
----@param cb fun(name: string)
+---@param cb? fun(err: libsql.AnyError?, name: string?)
+---@return string name
 function Rows:column_name(index, cb) end
 
 ---@param index integer
@@ -38,20 +296,77 @@ function Rows:column_name(index, cb) end
 function Rows:column_name_sync(index) end
 
 ---@param index integer
----@param cb fun(type: string)
+---@param cb? fun(err: libsql.AnyError?, type: string?)
+---@return string type
 function Rows:column_type(index, cb) end
 
 ---@param index integer
 ---@return string
 function Rows:column_type_sync(index) end
 
+---@class libsql.CollectOptions
+---Maximum number of rows to fetch. Defaults to all remaining rows.
+---@field limit integer?
+---Shape of each row: keyed by column name ("map", the default) or positional ("list").
+---@field as ("map" | "list")?
+---Columns to decode, by name, in addition to those the rows were queried with.
+---@field decode table<string, libsql.Format>?
+---Defaults to the setting the rows were queried with.
+---@field json_null libsql.NullMode?
+
+---"json": JSON text stored as `TEXT` or `BLOB`.
+---@alias libsql.Format "json"
+
+---What a null becomes: "null" for `vim.NIL`, keeping its place in arrays and tables, or "nil".
+---JSON nulls default to "null", and SQL `NULL`s to "nil".
+---@alias libsql.NullMode "null" | "nil"
+
+---Fetches the remaining rows in one call. The callback can also be passed alone, as in
+---`rows:collect(cb)`.
+---@param options libsql.CollectOptions?
+---@param cb? fun(err: libsql.AnyError?, rows: table[]?)
+---@return table[] rows
+---@overload fun(self: libsql.Rows, cb: fun(err: libsql.AnyError?, rows: table[]?)): table[]
+function Rows:collect(options, cb) end
+
+---@param options libsql.CollectOptions?
+---@return table[]
+function Rows:collect_sync(options) end
+
+---Releases the rows without reading the rest. Further calls on them fail. Also runs when a
+---`local rows <close>` goes out of scope.
+---@param cb? fun(err: libsql.AnyError?)
+function Rows:close(cb) end
+
+function Rows:close_sync() end
+
+---Columns can also be read as fields (`row.email`) unless shadowed by a method. `#row` is the
+---column count. Use `row:pairs()` to iterate `name, value`: Neovim's LuaJIT ignores `__pairs`,
+---so `pairs(row)` only works on runtimes built with Lua 5.2 compatibility.
 ---@class libsql.Row
+---@field [string] any
+---@operator len: integer
 local Row = {}
 
----@param index integer
+---@return table<string, any>
+function Row:to_table() end
+
+---@return any[]
+function Row:to_list() end
+
+---Iterates the columns in order, as in `for name, value in row:pairs() do`.
+---@return fun(): string?, any
+function Row:pairs() end
+
+---Blobs are returned as Lua strings, and decoded columns as Lua values.
+---@param index integer|string 0-based column index or column name.
 ---@return any
 function Row:get(index) end
 
+---@param index integer|string 0-based column index or column name.
+---@return libsql.Blob?
+function Row:get_blob(index) end
+
 ---@return integer
 function Row:column_count() end
 
@@ -66,30 +381,255 @@ function Row:column_type(index) end
 ---@class libsql.Database
 local Database = {}
 
----@param cb fun(conn: libsql.Connection)
+---Opens a new connection, separate from the pool. In-memory databases have a single connection,
+---since every new one would open an empty database of its own: `connect` returns it, and the
+---pool hands it out to one `with_connection` call at a time.
+---@param cb? fun(err: libsql.AnyError?, conn: libsql.Connection?)
+---@return libsql.Connection conn
 function Database:connect(cb) end
 
 ---@return libsql.Connection
 function Database:connect_sync() end
 
+---@class libsql.SyncInfo
+---Replication index the replica is at after syncing.
+---@field frame_no integer?
+
+---Pulls new frames from the primary into an embedded replica.
+---@param cb? fun(err: libsql.AnyError?, info: libsql.SyncInfo?)
+---@return libsql.SyncInfo info
+function Database:sync(cb) end
+
+---@return libsql.SyncInfo
+function Database:sync_sync() end
+
+---Checks a connection out of the pool and runs `f(conn)` in a new coroutine, where async methods
+---can be called without callbacks. The connection goes back to the pool once `f` returns, so it
+---must not be used after that. Waits for a free connection if the pool is at `max_size`.
+---@generic T
+---@param f fun(conn: libsql.Connection): T
+---@param cb? fun(err: libsql.AnyError?, result: T?)
+---@return T result
+function Database:with_connection(f, cb) end
+
+---@class libsql.PoolStats
+---@field max_size integer
+---Open connections waiting to be checked out.
+---@field idle integer
+---Connections currently checked out.
+---@field in_use integer
+---Connections opened since the database was created.
+---@field created integer
+
+---@return libsql.PoolStats
+function Database:pool_stats() end
+
+---`conn:schema()`, on a connection from the pool.
+---@param cb? fun(err: libsql.AnyError?, schema: libsql.Schema?)
+---@return libsql.Schema schema
+function Database:schema(cb) end
+
+---@return libsql.Schema
+function Database:schema_sync() end
+
+---Closes the database and its idle pooled connections. Connections already opened from it stay
+---usable until they are closed. Also runs when a `local db <close>` goes out of scope.
+---@param cb? fun(err: libsql.AnyError?)
+function Database:close(cb) end
+
+function Database:close_sync() end
+
+---Binary data. Bind it as a parameter to store a `BLOB`.
+---@class libsql.Blob
+---@operator len: integer
+local Blob = {}
+
+---@return integer
+function Blob:len() end
+
+---@return boolean
+function Blob:is_empty() end
+
+---Same semantics as `string.sub`.
+---@param i integer?
+---@param j integer?
+---@return libsql.Blob
+function Blob:sub(i, j) end
+
+---@return string
+function Blob:hex() end
+
+---Returns the raw bytes as a Lua string.
+---@return string
+function Blob:tostring() end
+
+---The eventual result of an async call.
+---@class libsql.Future
+local Future = {}
+
+---Yields the current coroutine until the future settles. Raises the error if it failed.
+---@return any
+function Future:await() end
+
+---Runs the event loop until the future settles. Raises the error if it failed or timed out.
+---@param timeout_ms integer? Defaults to no limit.
+---@return any
+function Future:wait(timeout_ms) end
+
+---Errors skip `f` and pass through. If `f` returns a future, the new future follows it.
+---@param f fun(value: any): any
+---@return libsql.Future
+function Future:and_then(f) end
+
+---Values pass through untouched.
+---@param f fun(err: libsql.AnyError): any
+---@return libsql.Future
+function Future:catch(f) end
+
+---@return boolean
+function Future:is_done() end
+
+---Aborts the call and fails the future with a `libsql.Error` of kind "cancelled".
+---@return boolean cancelled false if the future had already settled.
+function Future:cancel() end
+
+---@alias libsql.ErrorKind
+---| "sqlite_failure"
+---| "hrana"
+---| "connection"
+---| "misuse"
+---| "conversion"
+---| "cancelled"
+---| "timeout"
+
+---An error reported by libsql, or a cancelled or timed out call. `tostring(err)` gives the full
+---message.
+---@class libsql.Error
+---@field kind libsql.ErrorKind
+---Extended SQLite result code, for SQLite failures where it is known.
+---@field code integer?
+---@field message string
+---The statement that failed, if the error came from running one.
+---@field sql string?
+
 ---@class libsql
 local LibSQL = {}
 
----@alias libsql.DatabaseKind "remote" | "local" | "memory"
+---Returns the `libsql.Error` behind an error caught with `pcall`, or nil if it is not one.
+---@param err any
+---@return libsql.Error?
+function LibSQL.error(err) end
+
+---Resolves to the list of values of `futures`, in order, or fails with the first error.
+---@param futures libsql.Future[]
+---@return libsql.Future
+function LibSQL.join(futures) end
+
+---Runs `f` in a new coroutine, where async methods can be called without callbacks.
+---@param f fun(): any
+---@return libsql.Future
+function LibSQL.spawn(f) end
+
+---@param data string
+---@return libsql.Blob
+function LibSQL.blob(data) end
+
+---Converts a date, seconds since the unix epoch or a `libsql.DateTable` in UTC, to bind it in
+---one of SQLite's date formats: `conn:execute(sql, { libsql.datetime(os.time()) })`. Fields of
+---a table other than `year`, `month` and `day` default to 0.
+---@param value number | libsql.DateTable
+---@param format libsql.DateFormat?
+---@return string | number
+function LibSQL.datetime(value, format) end
+
+---Encodes `value` in SQLite's binary JSONB format, for binding instead of JSON text. Needs SQLite
+---3.45 or later, which local databases do not have yet.
+---@param value any
+---@return libsql.Blob
+function LibSQL.jsonb(value) end
+
+---@alias libsql.DatabaseKind "remote" | "local" | "memory" | "replica"
 
 ---@class libsql.DatabaseConfig
 ---@field kind libsql.DatabaseKind
----Url for remote databases.
+---Url for remote databases and replicas.
 ---@field url string?
----Token for remote databases.
+---Token for remote databases and replicas.
 ---@field token string?
----Path for local databases.
+---Path for local databases and replicas.
 ---@field path string?
+---Seconds between automatic background syncs of a replica.
+---@field sync_interval number?
+---Whether a replica's own writes are visible locally before the write returns. Defaults to true.
+---@field read_your_writes boolean?
+---Default timeout in milliseconds for calls on the database's connections.
+---@field timeout_ms integer?
+---Connection pool used by `db:with_connection`, and for reads with `concurrent_reads`. Ignored
+---for in-memory databases, whose pool is their single connection.
+---@field pool libsql.PoolConfig?
+---Run `SELECT` queries made through `db:connect()` connections on pooled connections, so they
+---don't wait for other calls on the connection. Queries inside a transaction still run on the
+---connection itself. State local to a connection, such as temp tables, is not visible to these
+---reads. Not supported for in-memory databases. Defaults to false.
+---@field concurrent_reads boolean?
+---How values returned through the database's connections are converted to Lua.
+---@field types libsql.TypeOptions?
+
+---LuaJIT has a single number type, so `REAL` and `INTEGER` values both become numbers, and a
+---number with an integral value binds as an `INTEGER`. `row:column_type` tells them apart.
+---@class libsql.TypeOptions
+---What SQL `NULL` becomes. Defaults to "nil". `vim.NIL` binds as `NULL` either way.
+---@field null libsql.NullMode?
+---Return integers in columns declared `BOOLEAN` as booleans. Queries through a connection
+---prepare their statement first to read the declared types. Defaults to false.
+---@field booleans boolean?
+---What integers beyond ±2^53, which numbers can't hold exactly, become: "number" (the default)
+---or "string".
+---@field big_integers ("number" | "string")?
+---What dates in columns declared `DATE`, `DATETIME` or `TIMESTAMP` become: "number" for seconds
+---since the unix epoch, as `os.time()` returns, or "table" for a `libsql.DateTable`. ISO-8601
+---text and unix epoch integers are read, and so are reals: as julian days below 5373484.5 and
+---as unix epoch seconds otherwise, like SQLite's `auto` modifier. Values that aren't dates, and
+---dates when this is unset, are returned as stored.
+---@field dates ("number" | "table")?
+
+---A date in UTC, with the fields `os.date("!*t")` returns.
+---@class libsql.DateTable
+---@field year integer
+---@field month integer
+---@field day integer
+---@field hour integer?
+---@field min integer?
+---@field sec number?
+---@field wday integer?
+---@field yday integer?
+---@field isdst boolean?
+
+---How `libsql.datetime` stores a date: "text" (`YYYY-MM-DD HH:MM:SS`, the default), "date"
+---(`YYYY-MM-DD`), "unixepoch" (seconds) or "julian" (julian day number).
+---@alias libsql.DateFormat "text" | "date" | "unixepoch" | "julian"
+
+---@class libsql.PoolConfig
+---Most connections open at once. Defaults to 8.
+---@field max_size integer?
+---Milliseconds a connection may stay idle before it is closed. Defaults to no limit.
+---@field idle_timeout_ms integer?
+---Whether to run `SELECT 1` on an idle connection before handing it out. Defaults to false.
+---@field health_check boolean?
 
 ---@param config libsql.DatabaseConfig
----@param cb fun(conn: libsql.Database)
+---@param cb? fun(err: libsql.AnyError?, conn: libsql.Database?)
+---@return libsql.Database conn
 function LibSQL.new_db(config, cb) end
 
 ---@param config libsql.DatabaseConfig
 ---@return libsql.Database
 function LibSQL.new_db_sync(config) end
+
+---@class libsql.RuntimeConfig
+---Number of worker threads used to run queries. Defaults to the number of CPU cores.
+---@field worker_threads integer?
+
+---Configures the shared async runtime. Must be called before the first query.
+---@param config libsql.RuntimeConfig
+function LibSQL.configure_runtime(config) end
@@ -0,0 +1,70 @@
+use mlua::{MetaMethod, UserData};
+
+use crate::prelude::*;
+
+/// Binary data, used to bind `BLOB` parameters and optionally returned by `row:get_blob`.
+#[derive(Debug, Clone)]
+pub struct LuaBlob(Vec<u8>);
+
+impl LuaBlob {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        LuaBlob(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> mlua::Result<usize> {
+        Ok(self.0.len())
+    }
+
+    pub fn is_empty(&self) -> mlua::Result<bool> {
+        Ok(self.0.is_empty())
+    }
+
+    /// Same semantics as `string.sub`: 1-based, inclusive, negative indices count from the end.
+    pub fn sub(
+        &self,
+        (i, j): (Option<mlua::Integer>, Option<mlua::Integer>),
+    ) -> mlua::Result<LuaBlob> {
+        let len = self.0.len() as i64;
+        let start = match i.unwrap_or(1) {
+            i if i < 0 => (len + i + 1).max(1),
+            0 => 1,
+            i => i,
+        };
+        let end = match j.unwrap_or(-1) {
+            j if j < 0 => len + j + 1,
+            j => j.min(len),
+        };
+
+        if start > end {
+            return Ok(LuaBlob(Vec::new()));
+        }
+        Ok(LuaBlob(self.0[(start - 1) as usize..end as usize].to_vec()))
+    }
+
+    pub fn hex(&self) -> mlua::Result<String> {
+        Ok(self.0.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    pub fn tostring<'lua>(&self, lua: &'lua Lua, _: ()) -> mlua::Result<mlua::String<'lua>> {
+        lua.create_string(&self.0)
+    }
+}
+
+impl UserData for LuaBlob {
+    fn add_fields<'lua, F: mlua::prelude::LuaUserDataFields<'lua, Self>>(_fields: &mut F) {}
+
+    fn add_methods<'lua, M: mlua::prelude::LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("len", Self::len.wrap());
+        methods.add_method("is_empty", Self::is_empty.wrap());
+        methods.add_method("sub", Self::sub.wrap());
+        methods.add_method("hex", Self::hex.wrap());
+        methods.add_method("tostring", Self::tostring.wrap());
+
+        methods.add_meta_method(MetaMethod::Len, Self::len.wrap());
+        methods.add_meta_method(MetaMethod::ToString, Self::tostring.wrap());
+    }
+}
@@ -1,55 +1,522 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use libsql_nvim_derive::luv_async;
+use libsql_nvim_derive::lua_methods;
 use mlua::{FromLua, OwnedFunction, UserData};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, RwLockMappedWriteGuard, RwLockReadGuard, RwLockWriteGuard};
 
+use crate::error::SqlResultExt;
+use crate::json::{Decode, Format};
+use crate::pool::Pool;
+use crate::schema::Schema;
+use crate::stmt::LuaStatement;
+use crate::task::Timeout;
+use crate::transaction::{LuaTransaction, LuaTransactionBehavior, PendingRollback};
+use crate::types::{NullMode, TypeOptions};
 use crate::{prelude::*, rows::LuaRows, ser::LuaSerializer};
 
+/// A connection shared by the handles to it, and the statements and transactions created from it.
+pub type SharedConnection = Arc<ConnectionState>;
+
+/// A connection and the state its handles share.
+///
+/// Calls take the write lock with `lock`, so they run on the connection one at a time. The read
+/// lock is only taken to look at the connection's state.
+pub struct ConnectionState {
+    /// `None` once it has been closed.
+    conn: RwLock<Option<libsql::Connection>>,
+    /// Whether the connection was outside of a transaction after its last call.
+    autocommit: AtomicBool,
+    /// Rollback of a dropped transaction that calls wait for.
+    rollback: PendingRollback,
+    /// Whether this is the one connection of an in-memory database, which closing would destroy.
+    in_memory: bool,
+}
+
+impl ConnectionState {
+    pub fn new(conn: libsql::Connection) -> SharedConnection {
+        ConnectionState::open(conn, false)
+    }
+
+    /// The connection every handle to an in-memory database uses. Closing a handle only detaches
+    /// it, so the others keep the data.
+    pub fn in_memory(conn: libsql::Connection) -> SharedConnection {
+        ConnectionState::open(conn, true)
+    }
+
+    fn open(conn: libsql::Connection, in_memory: bool) -> SharedConnection {
+        Arc::new(ConnectionState {
+            conn: RwLock::new(Some(conn)),
+            autocommit: AtomicBool::new(true),
+            rollback: PendingRollback::default(),
+            in_memory,
+        })
+    }
+
+    /// Takes the connection for a call, once the rollback of a dropped transaction is done.
+    pub async fn lock(&self) -> RwLockWriteGuard<'_, Option<libsql::Connection>> {
+        self.rollback.wait().await;
+        self.conn.write().await
+    }
+
+    /// The connection, unless a call is running on it.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, Option<libsql::Connection>>> {
+        self.conn.try_read().ok()
+    }
+
+    /// Rolls back `tx`, dropped without being committed, in the background. Calls wait for it.
+    pub(crate) fn roll_back(&self, tx: libsql::Transaction) {
+        self.rollback.start(tx);
+    }
+
+    /// Records whether `conn` is in a transaction, for `LuaConnection::readers_for` to check while
+    /// a call is running on it.
+    fn track(&self, conn: &libsql::Connection) {
+        self.autocommit
+            .store(conn.is_autocommit(), Ordering::Relaxed);
+    }
+}
+
+fn closed() -> mlua::Error {
+    mlua::Error::RuntimeError("connection is closed".to_string())
+}
+
 #[derive(Clone)]
-pub struct LuaConnection(Arc<RwLock<libsql::Connection>>);
+pub struct LuaConnection {
+    conn: SharedConnection,
+    /// Applies to calls through this connection and the statements and transactions created from
+    /// it.
+    timeout: Timeout,
+    /// Pool that read-only queries run on, so they don't queue behind other calls on this
+    /// connection.
+    readers: Option<Arc<Pool>>,
+    /// Columns decoded in the rows returned through this connection and the statements and
+    /// transactions created from it.
+    decode: Arc<Decode>,
+    /// Conversions of the values returned through this connection and the statements and
+    /// transactions created from it.
+    types: TypeOptions,
+    /// Set when this handle to an in-memory database is closed, which leaves the connection open
+    /// for the other handles.
+    detached: Arc<AtomicBool>,
+}
 
+#[lua_methods]
 impl LuaConnection {
-    pub fn new(conn: Arc<RwLock<libsql::Connection>>) -> Self {
-        LuaConnection(conn)
+    pub fn new(conn: SharedConnection, timeout: Option<Duration>, types: TypeOptions) -> Self {
+        LuaConnection {
+            conn,
+            timeout: Timeout::new(timeout),
+            readers: None,
+            decode: Arc::default(),
+            types,
+            detached: Arc::default(),
+        }
+    }
+
+    /// Runs read-only queries on connections from `readers` while outside of a transaction.
+    pub fn with_readers(mut self, readers: Arc<Pool>) -> Self {
+        self.readers = Some(readers);
+        self
+    }
+
+    /// Sets the timeout for calls, or removes it when `timeout_ms` is nil.
+    #[lua_method]
+    pub fn set_timeout(&self, timeout_ms: Option<u64>) -> mlua::Result<()> {
+        self.timeout.set(timeout_ms.map(Duration::from_millis));
+        Ok(())
+    }
+
+    /// Returns a handle to the same connection with its own timeout, e.g. for a single call.
+    #[lua_method]
+    pub fn with_timeout(&self, timeout_ms: Option<u64>) -> mlua::Result<LuaConnection> {
+        Ok(LuaConnection {
+            timeout: Timeout::new(timeout_ms.map(Duration::from_millis)),
+            ..self.clone()
+        })
+    }
+
+    /// Returns a handle to the same connection whose queries decode `columns`, e.g. for a single
+    /// call. Queries through statements and transactions created from it decode them too.
+    #[lua_method]
+    pub fn with_decode(
+        &self,
+        (columns, json_null): (HashMap<String, Format>, Option<NullMode>),
+    ) -> mlua::Result<LuaConnection> {
+        Ok(LuaConnection {
+            decode: Arc::new(Decode {
+                columns,
+                json_null: json_null.unwrap_or(NullMode::Null),
+            }),
+            ..self.clone()
+        })
+    }
+
+    /// Returns a handle to the same connection that converts values with `types`, on top of the
+    /// options it already has.
+    #[lua_method]
+    pub fn with_types(&self, types: TypeOptions) -> mlua::Result<LuaConnection> {
+        Ok(LuaConnection {
+            types: self.types.merge(types),
+            ..self.clone()
+        })
     }
 
-    #[luv_async]
+    #[lua_method(async, sync, timeout = self.timeout.get())]
     pub async fn execute(
         &self,
-        (sql, params, cb): (String, ParamsList, OwnedFunction),
+        (sql, params, cb): (String, ParamsList, Option<OwnedFunction>),
     ) -> mlua::Result<u64> {
-        let params = params;
-        let conn = self.0.write().await;
+        let conn = self.lock().await?;
 
-        conn.execute(&sql, params.0).await.into_lua_err()
+        let affected = execute(&conn, &sql, params).await;
+        self.conn.track(&conn);
+
+        affected
     }
 
-    #[luv_async]
+    #[lua_method(async, sync, timeout = self.timeout.get())]
     pub async fn query(
         &self,
-        (sql, params, cb): (String, ParamsList, OwnedFunction),
+        (sql, params, cb): (String, ParamsList, Option<OwnedFunction>),
     ) -> mlua::Result<LuaRows> {
-        let conn = self.0.write().await;
+        if let Some(readers) = self.readers_for(&sql) {
+            // The connection goes back to the pool as soon as the query has started. SQLite
+            // allows other statements on it while these rows are read, and holding it until the
+            // rows are garbage-collected could exhaust the pool.
+            let lease = Arc::clone(readers).checkout().await?;
+            let conn = lease.conn().lock().await;
+            let conn = conn.as_ref().ok_or_else(closed)?;
+
+            let rows = LuaRows::query(conn, &sql, params, self.types).await?;
+
+            return Ok(rows.with_decode(Arc::clone(&self.decode)));
+        }
+
+        let conn = self.lock().await?;
+
+        let rows = LuaRows::query(&conn, &sql, params, self.types).await;
+        self.conn.track(&conn);
+
+        mlua::Result::Ok(rows?.with_decode(Arc::clone(&self.decode)))
+    }
+
+    /// Runs every statement in `sql`. libsql does not report per-statement results for batches.
+    #[lua_method(async, sync, timeout = self.timeout.get())]
+    pub async fn execute_batch(
+        &self,
+        (sql, cb): (String, Option<OwnedFunction>),
+    ) -> mlua::Result<()> {
+        let conn = self.lock().await?;
+
+        let done = conn.execute_batch(&sql).await.with_sql(&sql);
+        self.conn.track(&conn);
+
+        done
+    }
+
+    #[lua_method(async, sync, timeout = self.timeout.get())]
+    pub async fn prepare(
+        &self,
+        (sql, cb): (String, Option<OwnedFunction>),
+    ) -> mlua::Result<LuaStatement> {
+        let conn = self.lock().await?;
+
+        let stmt = conn.prepare(&sql).await.with_sql(&sql)?;
+
+        mlua::Result::Ok(LuaStatement::new(
+            stmt,
+            sql,
+            Arc::clone(&self.conn),
+            self.timeout.clone(),
+            Arc::clone(&self.decode),
+            self.types,
+        ))
+    }
+
+    #[lua_method(async, sync, timeout = self.timeout.get())]
+    pub async fn transaction(
+        &self,
+        (behavior, cb): (Option<LuaTransactionBehavior>, Option<OwnedFunction>),
+    ) -> mlua::Result<LuaTransaction> {
+        let behavior = behavior.unwrap_or(LuaTransactionBehavior::Deferred);
+        let conn = self.lock().await?;
+
+        let tx = conn
+            .transaction_with_behavior(behavior.into())
+            .await
+            .into_sql_err()?;
+        self.conn.autocommit.store(false, Ordering::Relaxed);
+
+        mlua::Result::Ok(LuaTransaction::new(
+            tx,
+            Arc::clone(&self.conn),
+            self.timeout.clone(),
+            Arc::clone(&self.decode),
+            self.types,
+        ))
+    }
+
+    /// Rowid of the last row inserted through this connection.
+    #[lua_method]
+    pub fn last_insert_rowid(&self) -> mlua::Result<i64> {
+        Ok(self.idle()?.last_insert_rowid())
+    }
+
+    /// Rows changed by the last `INSERT`, `UPDATE` or `DELETE`.
+    #[lua_method]
+    pub fn changes(&self) -> mlua::Result<u64> {
+        Ok(self.idle()?.changes())
+    }
 
-        let rows = conn.query(&sql, params.0).await.into_lua_err()?;
+    /// Whether the connection is outside of a transaction.
+    #[lua_method]
+    pub fn is_autocommit(&self) -> mlua::Result<bool> {
+        Ok(self.idle()?.is_autocommit())
+    }
+
+    /// Rows changed since the connection was opened. libsql does not track this on the client, so
+    /// unlike the other metadata it is a query.
+    #[lua_method(async, sync, timeout = self.timeout.get())]
+    pub async fn total_changes(&self, cb: Option<OwnedFunction>) -> mlua::Result<u64> {
+        const SQL: &str = "SELECT total_changes()";
+        let conn = self.lock().await?;
+
+        let mut rows = conn.query(SQL, ()).await.with_sql(SQL)?;
+        let row = rows.next().await.with_sql(SQL)?.ok_or_else(|| {
+            mlua::Error::RuntimeError("total_changes() returned no rows".to_string())
+        })?;
+
+        row.get::<u64>(0).with_sql(SQL)
+    }
+
+    /// Tables, views, indexes and triggers of the main database, with their columns and foreign
+    /// keys.
+    #[lua_method(async, sync, timeout = self.timeout.get())]
+    pub async fn schema(&self, cb: Option<OwnedFunction>) -> mlua::Result<Schema> {
+        let conn = self.lock().await?;
+
+        Schema::read(&conn).await
+    }
+
+    /// Closes the connection, once the calls running on it are done. Further calls on it, through
+    /// any handle, fail. Statements and transactions created from it keep it open in libsql until
+    /// they are collected.
+    ///
+    /// The handles to an in-memory database share the connection that holds its data, so there
+    /// only this handle is closed, and the connection stays open for the others.
+    #[lua_method(async, sync)]
+    pub async fn close(&self, cb: Option<OwnedFunction>) -> mlua::Result<()> {
+        if self.conn.in_memory {
+            self.detached.store(true, Ordering::Relaxed);
+            return mlua::Result::Ok(());
+        }
+        self.conn.lock().await.take();
 
-        mlua::Result::Ok(LuaRows::new(rows))
+        mlua::Result::Ok(())
     }
+
+    /// Takes the connection for a call. Fails if it or this handle has been closed.
+    async fn lock(&self) -> mlua::Result<RwLockMappedWriteGuard<'_, libsql::Connection>> {
+        if self.detached.load(Ordering::Relaxed) {
+            return Err(closed());
+        }
+
+        RwLockWriteGuard::try_map(self.conn.lock().await, Option::as_mut).map_err(|_| closed())
+    }
+
+    /// The pool to run `sql` on instead of this connection, if it is a read-only query that
+    /// doesn't need to see an open transaction.
+    fn readers_for(&self, sql: &str) -> Option<&Arc<Pool>> {
+        let readers = self.readers.as_ref()?;
+        let autocommit = match self.conn.try_read() {
+            Some(conn) => conn.as_ref().is_some_and(libsql::Connection::is_autocommit),
+            None => self.conn.autocommit.load(Ordering::Relaxed),
+        };
+
+        (autocommit && is_read_only(sql)).then_some(readers)
+    }
+
+    /// The connection, for reading its state from the Lua thread. Fails instead of blocking the
+    /// editor while a call is running on it.
+    fn idle(&self) -> mlua::Result<RwLockReadGuard<'_, libsql::Connection>> {
+        if self.detached.load(Ordering::Relaxed) {
+            return Err(closed());
+        }
+
+        let conn = self.conn.try_read().ok_or_else(|| {
+            mlua::Error::RuntimeError("connection is busy with another call".to_string())
+        })?;
+
+        RwLockReadGuard::try_map(conn, Option::as_ref).map_err(|_| closed())
+    }
+}
+
+/// Whether `sql` is a `SELECT`, skipping leading whitespace and comments. Deliberately narrow: a
+/// `WITH` may lead into an `INSERT`, and anything else may write.
+fn is_read_only(sql: &str) -> bool {
+    let mut rest = sql;
+    loop {
+        rest = rest.trim_start();
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            break;
+        }
+    }
+
+    let keyword = rest
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or_default();
+    keyword.eq_ignore_ascii_case("select")
+}
+
+/// Query parameters built from a Lua table.
+///
+/// A sequence (`{ 1, "a" }`) binds positional placeholders. A table with string keys
+/// (`{ [":id"] = 1, name = "a" }`) binds named placeholders. The `:`, `@` and `$` prefixes are
+/// interchangeable, and keys may leave them out: `id`, `:id` and `@id` all bind `:id`, `@id` and
+/// `$id`. `nil` binds nothing.
+pub struct ParamsList(pub(crate) libsql::params::Params);
+
+impl ParamsList {
+    /// `key` without its prefix. Fails if nothing is left.
+    fn bare_name(key: &str) -> mlua::Result<&str> {
+        let name = key.strip_prefix([':', '@', '$']).unwrap_or(key);
+        if name.is_empty() {
+            return Err(mlua::Error::RuntimeError(format!(
+                "invalid parameter name {key:?}"
+            )));
+        }
+        Ok(name)
+    }
+
+    pub(crate) fn is_named(&self) -> bool {
+        matches!(self.0, libsql::params::Params::Named(_))
+    }
+
+    /// The parameters to bind to `stmt`, with each named key replaced by the placeholders of
+    /// `stmt` with that name. Fails for keys that name none of them.
+    pub(crate) fn resolve(self, stmt: &libsql::Statement) -> mlua::Result<libsql::params::Params> {
+        let libsql::params::Params::Named(named) = self.0 else {
+            return Ok(self.0);
+        };
+
+        // Local statements number their placeholders from 1, remote ones from 0.
+        let placeholders: Vec<&str> = (0..=stmt.parameter_count() as i32)
+            .filter_map(|i| stmt.parameter_name(i))
+            .filter(|name| name.starts_with([':', '@', '$']))
+            .collect();
+        if placeholders.is_empty() {
+            // Statements on remote databases don't know their placeholders before they run, so
+            // the keys are sent as given for the server to match.
+            return Ok(libsql::params::Params::Named(named));
+        }
+
+        let mut resolved = Vec::with_capacity(named.len());
+        for (key, value) in named {
+            let name = Self::bare_name(&key)?;
+            let before = resolved.len();
+            for placeholder in placeholders.iter().filter(|p| p[1..] == *name) {
+                resolved.push((placeholder.to_string(), value.clone()));
+            }
+            if resolved.len() == before {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "statement has no parameter named {key:?}"
+                )));
+            }
+        }
+
+        Ok(libsql::params::Params::Named(resolved))
+    }
+}
+
+/// Runs `sql` on `conn`. Named parameters are resolved on a prepared statement first.
+pub(crate) async fn execute(
+    conn: &libsql::Connection,
+    sql: &str,
+    params: ParamsList,
+) -> mlua::Result<u64> {
+    if !params.is_named() {
+        return conn.execute(sql, params.0).await.with_sql(sql);
+    }
+
+    let mut stmt = conn.prepare(sql).await.with_sql(sql)?;
+    let params = params.resolve(&stmt)?;
+    let affected = stmt.execute(params).await.with_sql(sql)?;
+
+    Ok(affected as u64)
 }
 
-pub struct ParamsList(Vec<libsql::Value>);
+impl From<libsql::params::Params> for ParamsList {
+    fn from(params: libsql::params::Params) -> Self {
+        ParamsList(params)
+    }
+}
 
 impl FromLua<'_> for ParamsList {
     fn from_lua(value: mlua::Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        if value.is_nil() {
+            return Ok(ParamsList(libsql::params::Params::None));
+        }
+
         let table = mlua::Table::from_lua(value, lua)?;
-        let mut params = Vec::new();
-        for i in table.len()?..=1 {
-            let value = table.get(i)?;
-            let value = LuaSerializer::new(value).into_sql()?;
-            params.push(value);
+        let mut positional = Vec::new();
+        let mut named: Vec<(String, libsql::Value)> = Vec::new();
+        for pair in table.pairs::<mlua::Value, mlua::Value>() {
+            let (key, value) = pair?;
+            let value = LuaSerializer::new(lua, value).into_sql()?;
+            match key {
+                mlua::Value::Integer(i) => positional.push((i, value)),
+                mlua::Value::String(key) => {
+                    let key = key.to_str()?;
+                    let name = Self::bare_name(key)?;
+                    if named
+                        .iter()
+                        .any(|(other, _)| Self::bare_name(other).is_ok_and(|other| other == name))
+                    {
+                        return Err(mlua::Error::RuntimeError(format!(
+                            "parameter {name} is given more than once"
+                        )));
+                    }
+                    named.push((key.to_string(), value));
+                }
+                key => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "parameter keys must be integers or strings, got {}",
+                        key.type_name()
+                    )))
+                }
+            }
+        }
+
+        match (positional.is_empty(), named.is_empty()) {
+            (true, true) => Ok(ParamsList(libsql::params::Params::None)),
+            (false, true) => {
+                positional.sort_unstable_by_key(|(i, _)| *i);
+                if positional
+                    .iter()
+                    .enumerate()
+                    .any(|(n, (i, _))| *i != n as i64 + 1)
+                {
+                    return Err(mlua::Error::RuntimeError(
+                        "positional parameters must be a sequence starting at 1".to_string(),
+                    ));
+                }
+                Ok(ParamsList(libsql::params::Params::Positional(
+                    positional.into_iter().map(|(_, value)| value).collect(),
+                )))
+            }
+            (true, false) => Ok(ParamsList(libsql::params::Params::Named(named))),
+            (false, false) => Err(mlua::Error::RuntimeError(
+                "cannot mix positional and named parameters".to_string(),
+            )),
         }
-        Ok(ParamsList(params))
     }
 }
 
@@ -57,7 +524,211 @@ impl UserData for LuaConnection {
     fn add_fields<'lua, F: mlua::prelude::LuaUserDataFields<'lua, Self>>(_fields: &mut F) {}
 
     fn add_methods<'lua, M: mlua::prelude::LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("execute", Self::execute.wrap());
-        methods.add_method("query", Self::query.wrap());
+        Self::add_lua_methods(methods);
+
+        methods.add_meta_method("__close", Self::close_sync.wrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::db::LuaDatabase;
+    use crate::error::{ErrorKind, LuaSqlError};
+    use crate::pool::PoolConfig;
+
+    /// Counted in a single step, so the whole query runs inside `query`.
+    const SLOW_SELECT: &str = "SELECT count(*) FROM n AS a, n AS b WHERE a.x + b.x = 2500";
+
+    fn no_params() -> ParamsList {
+        ParamsList(libsql::params::Params::None)
+    }
+
+    /// A database file, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// A connection to a new database file with a table `n` of the numbers 1 to 2000.
+    fn connect(name: &str) -> (LuaConnection, TempFile) {
+        let path =
+            std::env::temp_dir().join(format!("libsql-nvim-{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let file = TempFile(path.clone());
+
+        let db = crate::runtime::block_on(async {
+            libsql::Builder::new_local(path)
+                .build()
+                .await
+                .into_sql_err()
+        })
+        .unwrap();
+        let conn = LuaDatabase::new(db, None, PoolConfig::default())
+            .with_concurrent_reads(true)
+            .connect_sync()
+            .unwrap();
+
+        conn.execute_batch_sync(
+            "CREATE TABLE n (x INTEGER);
+             WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 2000)
+             INSERT INTO n SELECT x FROM c;"
+                .to_string(),
+        )
+        .unwrap();
+        (conn, file)
+    }
+
+    #[test]
+    fn selects_run_beside_other_calls() {
+        let (conn, _file) = connect("overlap");
+        let readers = Arc::clone(conn.readers.as_ref().unwrap());
+
+        // Another call running on the connection, and a query reading on a pooled connection.
+        let busy = crate::runtime::block_on(async { Ok(conn.conn.lock().await) }).unwrap();
+        let reading = crate::runtime::block_on(Arc::clone(&readers).checkout()).unwrap();
+
+        // Waiting on either would time out.
+        let rows = conn
+            .with_timeout(Some(5000))
+            .unwrap()
+            .query_sync((SLOW_SELECT.to_string(), no_params()))
+            .unwrap();
+        assert!(rows.next_sync().unwrap().is_some());
+
+        // The query opened a second pooled connection, which is idle again now that it started.
+        let stats = readers.stats();
+        assert_eq!((stats.in_use, stats.created), (1, 2));
+        let second = crate::runtime::block_on(Arc::clone(&readers).checkout()).unwrap();
+        assert_eq!(readers.stats().in_use, 2);
+
+        drop((busy, reading, second));
+    }
+
+    #[test]
+    fn sync_calls_time_out() {
+        let (conn, _file) = connect("timeout");
+        let busy = crate::runtime::block_on(async { Ok(conn.conn.lock().await) }).unwrap();
+
+        let err = conn
+            .with_timeout(Some(50))
+            .unwrap()
+            .execute_sync(("DELETE FROM n".to_string(), no_params()))
+            .unwrap_err();
+        assert_eq!(
+            LuaSqlError::find(&err).map(LuaSqlError::kind),
+            Some(ErrorKind::Timeout)
+        );
+
+        // The call was dropped with the timeout, rather than left waiting to run.
+        drop(busy);
+        let deleted = conn
+            .execute_sync(("DELETE FROM n WHERE x = 2000".to_string(), no_params()))
+            .unwrap();
+        assert_eq!(deleted, 1);
+    }
+
+    #[test]
+    fn reads_in_a_transaction_stay_on_the_connection() {
+        let (conn, _file) = connect("transaction");
+
+        conn.execute_sync(("BEGIN".to_string(), no_params()))
+            .unwrap();
+        conn.execute_sync(("INSERT INTO n VALUES (0)".to_string(), no_params()))
+            .unwrap();
+
+        // A pooled connection would not see the uncommitted row.
+        let rows = conn
+            .query_sync(("SELECT x FROM n WHERE x = 0".to_string(), no_params()))
+            .unwrap();
+        assert!(rows.next_sync().unwrap().is_some());
+    }
+
+    fn named(key: &str, value: i64) -> ParamsList {
+        ParamsList(libsql::params::Params::Named(vec![(
+            key.to_string(),
+            libsql::Value::Integer(value),
+        )]))
+    }
+
+    #[test]
+    fn named_parameters_bind_with_any_prefix() {
+        let (conn, _file) = connect("named");
+
+        for placeholder in [":x", "@x", "$x"] {
+            for key in ["x", ":x", "@x", "$x"] {
+                let sql = format!("SELECT x FROM n WHERE x = {placeholder}");
+                let rows = conn.query_sync((sql.clone(), named(key, 7))).unwrap();
+                assert!(
+                    rows.next_sync().unwrap().is_some(),
+                    "{key} did not bind {placeholder} in query"
+                );
+                // Open rows keep the database locked for the UPDATE below.
+                drop(rows);
+
+                let stmt = conn.prepare_sync(sql).unwrap();
+                let rows = stmt.query_sync(named(key, 7)).unwrap();
+                assert!(
+                    rows.next_sync().unwrap().is_some(),
+                    "{key} did not bind {placeholder} in a statement"
+                );
+                drop((rows, stmt));
+
+                let sql = format!("UPDATE n SET x = x WHERE x = {placeholder}");
+                assert_eq!(
+                    conn.execute_sync((sql, named(key, 7))).unwrap(),
+                    1,
+                    "{key} did not bind {placeholder} in execute"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_named_parameters_are_rejected() {
+        let (conn, _file) = connect("unknown");
+
+        let err = conn
+            .query_sync(("SELECT x FROM n WHERE x = :x".to_string(), named("y", 7)))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("no parameter named \"y\""),
+            "{err}"
+        );
+
+        let err = conn
+            .execute_sync(("DELETE FROM n WHERE x = @x".to_string(), named(":y", 7)))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("no parameter named \":y\""),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn calls_wait_for_the_rollback_of_a_dropped_transaction() {
+        let (conn, _file) = connect("rollback");
+
+        for _ in 0..50 {
+            let tx = conn.transaction_sync(None).unwrap();
+            tx.execute_sync(("INSERT INTO n VALUES (0)".to_string(), no_params()))
+                .unwrap();
+            drop(tx);
+
+            // Fails with "cannot start a transaction within a transaction" if the BEGIN gets to
+            // the connection before the ROLLBACK.
+            drop(conn.transaction_sync(None).unwrap());
+        }
+
+        let rows = conn
+            .query_sync(("SELECT x FROM n WHERE x = 0".to_string(), no_params()))
+            .unwrap();
+        assert!(rows.next_sync().unwrap().is_none());
     }
 }
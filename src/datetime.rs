@@ -0,0 +1,395 @@
+//! Conversion between SQLite's date and time formats and Lua timestamps and date tables.
+//!
+//! SQLite stores dates as ISO-8601 text, seconds since the unix epoch or julian day numbers, all
+//! in UTC. On the Lua side a date is a number of seconds, as `os.time()` returns, or a table
+//! with the fields of `os.date("!*t")`.
+
+use libsql_nvim_derive::FromLuaSerde;
+use mlua::serde::LuaSerdeExt;
+use mlua::Value;
+
+use crate::prelude::*;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Julian day number of 1970-01-01 00:00:00 UTC.
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2_440_587.5;
+
+/// Julian day number of 9999-12-31 23:59:59, past which SQLite's `auto` modifier reads numbers as
+/// seconds since the unix epoch.
+const MAX_JULIAN_DAY: f64 = 5_373_484.5;
+
+/// What dates read from the database become.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub enum DateMode {
+    /// Seconds since the unix epoch.
+    #[serde(rename = "number")]
+    Number,
+    /// A table like `os.date("!*t")` returns.
+    #[serde(rename = "table")]
+    Table,
+}
+
+/// SQLite format `libsql.datetime` produces.
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub enum DateFormat {
+    /// `YYYY-MM-DD HH:MM:SS`, with milliseconds when there are any.
+    #[default]
+    #[serde(rename = "text")]
+    Text,
+    /// `YYYY-MM-DD`.
+    #[serde(rename = "date")]
+    Date,
+    /// Seconds since the unix epoch.
+    #[serde(rename = "unixepoch")]
+    UnixEpoch,
+    /// Julian day number.
+    #[serde(rename = "julian")]
+    Julian,
+}
+
+/// Seconds since the unix epoch, UTC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp(f64);
+
+impl Timestamp {
+    /// Reads a date stored in any of SQLite's formats. `None` if `value` isn't one.
+    ///
+    /// Reals are julian day numbers when in range for one and seconds since the unix epoch
+    /// otherwise, as with SQLite's `auto` modifier, so fractional seconds read back.
+    pub fn from_sql(value: &libsql::Value) -> Option<Timestamp> {
+        match value {
+            libsql::Value::Text(text) => Timestamp::parse(text),
+            libsql::Value::Integer(secs) => Some(Timestamp(*secs as f64)),
+            libsql::Value::Real(day) if (0.0..MAX_JULIAN_DAY).contains(day) => Some(Timestamp(
+                (day - UNIX_EPOCH_JULIAN_DAY) * SECONDS_PER_DAY as f64,
+            )),
+            libsql::Value::Real(secs) => Some(Timestamp(*secs)),
+            libsql::Value::Null | libsql::Value::Blob(_) => None,
+        }
+    }
+
+    /// Parses `YYYY-MM-DD`, optionally followed by ` HH:MM`, `:SS`, `.SSS` and a `Z` or `±HH:MM`
+    /// offset, with `T` allowed in place of the space.
+    fn parse(text: &str) -> Option<Timestamp> {
+        let mut cursor = Cursor(text.trim());
+
+        let year = cursor.digits(4)?;
+        cursor.expect(b'-')?;
+        let month = cursor.digits(2)?;
+        cursor.expect(b'-')?;
+        let day = cursor.digits(2)?;
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+
+        let mut secs = 0.0;
+        if cursor
+            .expect(b' ')
+            .or_else(|| cursor.expect(b'T'))
+            .is_some()
+        {
+            let hour = cursor.digits(2)?;
+            cursor.expect(b':')?;
+            let min = cursor.digits(2)?;
+            let mut sec = 0.0;
+            if cursor.expect(b':').is_some() {
+                sec = cursor.digits(2)? as f64;
+                if cursor.expect(b'.').is_some() {
+                    sec += cursor.fraction()?;
+                }
+            }
+            if hour > 23 || min > 59 || sec >= 60.0 {
+                return None;
+            }
+            secs = (hour * 3600 + min * 60) as f64 + sec;
+        }
+
+        let offset = match cursor.0.trim_start().as_bytes() {
+            [] | [b'Z' | b'z'] => 0,
+            [sign @ (b'+' | b'-'), ..] => {
+                let mut offset = Cursor(&cursor.0.trim_start()[1..]);
+                let hours = offset.digits(2)?;
+                offset.expect(b':')?;
+                let mins = offset.digits(2)?;
+                if !offset.0.is_empty() {
+                    return None;
+                }
+                let offset = hours * 3600 + mins * 60;
+                if *sign == b'-' {
+                    -offset
+                } else {
+                    offset
+                }
+            }
+            _ => return None,
+        };
+
+        let days = days_from_civil(year, month, day);
+        Some(Timestamp((days * SECONDS_PER_DAY - offset) as f64 + secs))
+    }
+
+    /// Reads seconds since the unix epoch, or a date table whose `year`, `month` and `day` are set
+    /// and whose other fields default to 0. Out-of-range fields carry over, as with `os.time`.
+    pub fn from_lua(value: Value) -> mlua::Result<Timestamp> {
+        match value {
+            Value::Integer(secs) => Ok(Timestamp(secs as f64)),
+            Value::Number(secs) => Ok(Timestamp(secs)),
+            Value::Table(table) => {
+                let field = |name: &str| -> mlua::Result<i64> {
+                    table.get::<_, Option<i64>>(name)?.ok_or_else(|| {
+                        mlua::Error::RuntimeError(format!("date table is missing field {name:?}"))
+                    })
+                };
+                let (year, month, day) = (field("year")?, field("month")?, field("day")?);
+                let hour = table.get::<_, Option<i64>>("hour")?.unwrap_or(0);
+                let min = table.get::<_, Option<i64>>("min")?.unwrap_or(0);
+                let sec = table.get::<_, Option<f64>>("sec")?.unwrap_or(0.0);
+
+                let year = year + (month - 1).div_euclid(12);
+                let month = (month - 1).rem_euclid(12) + 1;
+                let days = days_from_civil(year, month, 1) + day - 1;
+                Ok(Timestamp(
+                    (days * SECONDS_PER_DAY + hour * 3600 + min * 60) as f64 + sec,
+                ))
+            }
+            value => Err(mlua::Error::RuntimeError(format!(
+                "expected seconds or a date table, got {}",
+                value.type_name()
+            ))),
+        }
+    }
+
+    pub fn to_lua(self, lua: &Lua, mode: DateMode) -> mlua::Result<Value<'_>> {
+        match mode {
+            DateMode::Number => Ok(number(self.0)),
+            DateMode::Table => {
+                let whole = self.0.floor() as i64;
+                let days = whole.div_euclid(SECONDS_PER_DAY);
+                let time = whole.rem_euclid(SECONDS_PER_DAY);
+                let (year, month, day) = civil_from_days(days);
+
+                let table = lua.create_table_with_capacity(0, 9)?;
+                table.set("year", year)?;
+                table.set("month", month)?;
+                table.set("day", day)?;
+                table.set("hour", time / 3600)?;
+                table.set("min", time % 3600 / 60)?;
+                table.set("sec", number((time % 60) as f64 + (self.0 - whole as f64)))?;
+                // 1970-01-01 was a Thursday; Sunday is 1.
+                table.set("wday", (days + 4).rem_euclid(7) + 1)?;
+                table.set("yday", days - days_from_civil(year, 1, 1) + 1)?;
+                table.set("isdst", false)?;
+                Ok(Value::Table(table))
+            }
+        }
+    }
+
+    /// The value to bind to store this date in `format`.
+    pub fn to_sql(self, lua: &Lua, format: DateFormat) -> mlua::Result<Value<'_>> {
+        match format {
+            DateFormat::Date => {
+                let days = (self.0.floor() as i64).div_euclid(SECONDS_PER_DAY);
+                let (year, month, day) = civil_from_days(days);
+                format!("{year:04}-{month:02}-{day:02}").into_lua(lua)
+            }
+            DateFormat::Text => {
+                // Rounded as a whole, so that a fraction that rounds up carries into the seconds.
+                let millis = (self.0 * 1000.0).round() as i64;
+                let whole = millis.div_euclid(1000);
+                let days = whole.div_euclid(SECONDS_PER_DAY);
+                let time = whole.rem_euclid(SECONDS_PER_DAY);
+                let (year, month, day) = civil_from_days(days);
+
+                let mut text = format!(
+                    "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+                    time / 3600,
+                    time % 3600 / 60,
+                    time % 60
+                );
+                if millis.rem_euclid(1000) > 0 {
+                    text.push_str(&format!(".{:03}", millis.rem_euclid(1000)));
+                }
+                text.into_lua(lua)
+            }
+            DateFormat::UnixEpoch => Ok(number(self.0)),
+            DateFormat::Julian => {
+                (self.0 / SECONDS_PER_DAY as f64 + UNIX_EPOCH_JULIAN_DAY).into_lua(lua)
+            }
+        }
+    }
+}
+
+/// `n` as an integer when it is whole, so it prints without a fraction.
+fn number(n: f64) -> Value<'static> {
+    if n.fract() == 0.0 && n.abs() < (1u64 << 53) as f64 {
+        Value::Integer(n as i64)
+    } else {
+        Value::Number(n)
+    }
+}
+
+/// `libsql.datetime(value, format)`: `value`, seconds or a date table, in one of SQLite's
+/// formats.
+pub fn format<'lua>(
+    lua: &'lua Lua,
+    (value, format): (Value<'lua>, Option<DateFormat>),
+) -> mlua::Result<Value<'lua>> {
+    Timestamp::from_lua(value)?.to_sql(lua, format.unwrap_or_default())
+}
+
+struct Cursor<'a>(&'a str);
+
+impl Cursor<'_> {
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        let rest = self.0.strip_prefix(byte as char)?;
+        self.0 = rest;
+        Some(())
+    }
+
+    fn digits(&mut self, n: usize) -> Option<i64> {
+        let digits = self.0.get(..n)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        self.0 = &self.0[n..];
+        digits.parse().ok()
+    }
+
+    /// The digits after a decimal point, as a fraction.
+    fn fraction(&mut self) -> Option<f64> {
+        let len = self.0.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        let fraction = format!("0.{}", &self.0[..len]).parse().ok()?;
+        self.0 = &self.0[len..];
+        Some(fraction)
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, after Howard Hinnant's algorithm.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use luajit2_sys as _;
+
+    use super::*;
+
+    fn assert_close(Timestamp(actual): Timestamp, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn parse_reads_iso_8601() {
+        assert_close(Timestamp::parse("1970-01-01").unwrap(), 0.0);
+        assert_close(
+            Timestamp::parse("2024-02-29 12:34:56.789Z").unwrap(),
+            1_709_210_096.789,
+        );
+        assert_close(
+            Timestamp::parse("2024-02-29T12:34+02:00").unwrap(),
+            1_709_202_840.0,
+        );
+        assert_close(
+            Timestamp::parse("2024-02-29 08:34 -02:00").unwrap(),
+            1_709_202_840.0,
+        );
+
+        for invalid in [
+            "2024-13-01",
+            "2024-01-32",
+            "2024-01-01 24:00",
+            "2024-01-01 12:60",
+            "2024-01-01 12:00:00.",
+            "2024-01-01 12:00 +0200",
+            "2024-1-01",
+            "2024-01-01x",
+        ] {
+            assert_eq!(Timestamp::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn days_round_trip_through_civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-719_468), (0, 3, 1));
+
+        for days in (-1_000_000..1_000_000).step_by(997) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn from_lua_carries_months_and_days_over() {
+        let lua = Lua::new();
+        let date = |fields: &str| {
+            let table = lua.load(format!("return {{ {fields} }}")).eval().unwrap();
+            Timestamp::from_lua(Value::Table(table)).unwrap()
+        };
+
+        // 2024-02-01 and 2022-12-01.
+        assert_close(date("year = 2023, month = 14, day = 1"), 1_706_745_600.0);
+        assert_close(date("year = 2024, month = 1, day = 32"), 1_706_745_600.0);
+        assert_close(date("year = 2023, month = 0, day = 1"), 1_669_852_800.0);
+        assert_close(
+            date("year = 2024, month = 2, day = 1, hour = -1, min = 60, sec = 0.5"),
+            1_706_745_600.5,
+        );
+    }
+
+    #[test]
+    fn from_sql_tells_julian_days_from_unix_seconds() {
+        let real = |n| Timestamp::from_sql(&libsql::Value::Real(n)).unwrap();
+
+        assert_close(real(2_440_588.0), 43_200.0);
+        assert_close(real(1_709_210_096.789), 1_709_210_096.789);
+        assert_close(real(-1.5), -1.5);
+    }
+
+    #[test]
+    fn to_sql_rounds_milliseconds_into_the_seconds() {
+        let lua = Lua::new();
+        let text = |secs| -> String {
+            let value = Timestamp(secs).to_sql(&lua, DateFormat::Text).unwrap();
+            lua.unpack(value).unwrap()
+        };
+
+        assert_eq!(text(59.9995), "1970-01-01 00:01:00");
+        assert_eq!(text(86_399.999_6), "1970-01-02 00:00:00");
+        assert_eq!(text(0.9994), "1970-01-01 00:00:00.999");
+        assert_eq!(text(0.5), "1970-01-01 00:00:00.500");
+        assert_eq!(text(-0.25), "1969-12-31 23:59:59.750");
+    }
+}
@@ -1,18 +1,38 @@
-use std::sync::{Arc, Weak};
+use std::sync::Arc;
+use std::time::Duration;
 
-use libsql_nvim_derive::{luv_async, FromLuaSerde};
+use libsql_nvim_derive::{lua_methods, luv_async, FromLuaSerde};
 use mlua::serde::LuaSerdeExt;
-use mlua::{ExternalResult, OwnedFunction};
+use mlua::{FromLua, IntoLua, OwnedFunction};
 use tokio::sync::RwLock;
 
-use crate::conn::LuaConnection;
+use crate::conn::{ConnectionState, LuaConnection, SharedConnection};
+use crate::error::SqlResultExt;
+use crate::future::{LuaFuture, Pending};
+use crate::pool::{Lease, Pool, PoolConfig, PoolStats};
 use crate::prelude::*;
+use crate::schema::Schema;
+use crate::types::TypeOptions;
+
+/// A database shared by the handles to it. `None` once it has been closed.
+pub type SharedDatabase = Arc<RwLock<Option<libsql::Database>>>;
+
+pub(crate) fn closed() -> mlua::Error {
+    mlua::Error::RuntimeError("database is closed".to_string())
+}
 
 #[derive(Clone)]
 pub struct LuaDatabase {
-    db: Arc<RwLock<libsql::Database>>,
-    #[allow(unused)]
-    conn: Weak<RwLock<libsql::Connection>>,
+    db: SharedDatabase,
+    /// Connections handed out by `with_connection`, and used for reads with `concurrent_reads`.
+    pool: Arc<Pool>,
+    concurrent_reads: bool,
+    /// Default timeout for calls on connections to this database.
+    timeout: Option<Duration>,
+    /// Default conversions of values returned through connections to this database.
+    types: TypeOptions,
+    /// The one connection of an in-memory database, handed out by `connect` and the pool.
+    shared: Option<SharedConnection>,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, FromLuaSerde)]
@@ -23,42 +43,41 @@ pub enum LuaDatabaseKind {
     Local,
     #[serde(rename = "memory")]
     Memory,
+    #[serde(rename = "replica")]
+    Replica,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, FromLuaSerde)]
 pub struct LuaDatabaseConfig {
     pub(crate) kind: LuaDatabaseKind,
-    #[serde(alias = "path")]
     url: Option<String>,
     token: Option<String>,
+    path: Option<String>,
+    /// Seconds between automatic background syncs of a replica.
+    sync_interval: Option<f64>,
+    /// Whether a replica's own writes are visible locally before the write returns.
+    read_your_writes: Option<bool>,
+    /// Default timeout in milliseconds for calls on connections to the database.
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    pool: PoolConfig,
+    /// Whether read-only queries on a connection run on pooled connections, concurrently with
+    /// other calls on it. Defaults to false.
+    concurrent_reads: Option<bool>,
+    /// How values returned through connections to the database are converted to Lua.
+    #[serde(default)]
+    types: TypeOptions,
 }
 
-impl LuaDatabase {
-    pub fn new(db: libsql::Database) -> Self {
-        LuaDatabase {
-            db: Arc::new(RwLock::new(db)),
-            conn: Weak::new(),
-        }
-    }
-
-    #[luv_async]
-    async fn connect_impl(&self, cb: OwnedFunction) -> mlua::Result<LuaConnection> {
-        let conn = self.db.read().await.connect().into_lua_err()?;
-
-        mlua::Result::Ok(LuaConnection::new(Arc::new(RwLock::new(conn))))
-    }
-
-    #[luv_async]
-    async fn create_impl(
-        (config, cb): (LuaDatabaseConfig, OwnedFunction),
-    ) -> mlua::Result<LuaDatabase> {
-        let db = match config.kind {
+impl LuaDatabaseConfig {
+    async fn build(self) -> mlua::Result<libsql::Database> {
+        match self.kind {
             LuaDatabaseKind::Remote => {
                 let LuaDatabaseConfig {
                     url: Some(url),
                     token: Some(token),
                     ..
-                } = config
+                } = self
                 else {
                     return Err(mlua::Error::RuntimeError(
                         "url and token must be provided".to_string(),
@@ -68,10 +87,11 @@ impl LuaDatabase {
                 libsql::Builder::new_remote(url, token)
                     .build()
                     .await
-                    .into_lua_err()
+                    .into_sql_err()
             }
             LuaDatabaseKind::Local => {
-                let Some(path) = config.url else {
+                // `url` is accepted as the path of local databases for backwards compatibility.
+                let Some(path) = self.path.or(self.url) else {
                     return Err(mlua::Error::RuntimeError(
                         "path must be provided".to_string(),
                     ));
@@ -80,75 +100,228 @@ impl LuaDatabase {
                 libsql::Builder::new_local(path)
                     .build()
                     .await
-                    .into_lua_err()
+                    .into_sql_err()
             }
             LuaDatabaseKind::Memory => libsql::Builder::new_local(":memory:")
                 .build()
                 .await
-                .into_lua_err(),
-        }?;
+                .into_sql_err(),
+            LuaDatabaseKind::Replica => {
+                let LuaDatabaseConfig {
+                    path: Some(path),
+                    url: Some(url),
+                    token: Some(token),
+                    ..
+                } = self
+                else {
+                    return Err(mlua::Error::RuntimeError(
+                        "path, url and token must be provided".to_string(),
+                    ));
+                };
 
-        mlua::Result::Ok(LuaDatabase::new(db))
+                let mut builder = libsql::Builder::new_remote_replica(path, url, token)
+                    .read_your_writes(self.read_your_writes.unwrap_or(true));
+                if let Some(interval) = self.sync_interval {
+                    let interval = std::time::Duration::try_from_secs_f64(interval)
+                        .map_err(|e| mlua::Error::RuntimeError(format!("sync_interval: {e}")))?;
+                    builder = builder.sync_interval(interval);
+                }
+
+                builder.build().await.into_sql_err()
+            }
+        }
+    }
+}
+
+#[lua_methods]
+impl LuaDatabase {
+    pub fn new(db: libsql::Database, timeout: Option<Duration>, pool: PoolConfig) -> Self {
+        let db = Arc::new(RwLock::new(Some(db)));
+        let pool = Arc::new(Pool::new(Arc::clone(&db), pool));
+        pool.spawn_reaper();
+        LuaDatabase {
+            pool,
+            concurrent_reads: false,
+            db,
+            timeout,
+            types: TypeOptions::default(),
+            shared: None,
+        }
+    }
+
+    /// Makes `connect` and the pool hand out `conn` instead of opening connections, for in-memory
+    /// databases, where every new connection opens an empty database of its own.
+    pub fn with_shared_connection(mut self, conn: libsql::Connection) -> Self {
+        let conn = ConnectionState::in_memory(conn);
+        self.pool = Arc::new(Pool::single(Arc::clone(&self.db), Arc::clone(&conn)));
+        self.shared = Some(conn);
+        self
+    }
+
+    pub fn with_concurrent_reads(mut self, concurrent_reads: bool) -> Self {
+        self.concurrent_reads = concurrent_reads;
+        self
     }
 
-    pub fn connect_sync(&self) -> mlua::Result<LuaConnection> {
-        let conn = self.db.blocking_read().connect().into_lua_err()?;
-        Ok(LuaConnection::new(Arc::new(RwLock::new(conn))))
+    pub fn with_types(mut self, types: TypeOptions) -> Self {
+        self.types = types;
+        self
     }
 
-    pub fn connect(&self, cb: OwnedFunction) -> mlua::Result<()> {
-        if let Some(conn) = self.conn.upgrade() {
-            return cb.call(LuaConnection::new(conn));
+    /// Opens a new connection, separate from the pool. In-memory databases return their one
+    /// connection instead.
+    #[lua_method(async, sync)]
+    pub async fn connect(&self, cb: Option<OwnedFunction>) -> mlua::Result<LuaConnection> {
+        let db = self.db.read().await;
+        let db = db.as_ref().ok_or_else(closed)?;
+        let conn = match &self.shared {
+            Some(conn) => Arc::clone(conn),
+            None => ConnectionState::new(db.connect().into_sql_err()?),
+        };
+        let conn = LuaConnection::new(conn, self.timeout, self.types);
+
+        mlua::Result::Ok(if self.concurrent_reads {
+            conn.with_readers(Arc::clone(&self.pool))
+        } else {
+            conn
+        })
+    }
+
+    #[luv_async(sync)]
+    pub async fn create(
+        (config, cb): (LuaDatabaseConfig, Option<OwnedFunction>),
+    ) -> mlua::Result<LuaDatabase> {
+        let timeout = config.timeout_ms.map(Duration::from_millis);
+        let pool = config.pool.clone();
+        pool.validate()?;
+        let concurrent_reads = config.concurrent_reads.unwrap_or(false);
+        let types = config.types;
+        if concurrent_reads && matches!(config.kind, LuaDatabaseKind::Memory) {
+            // Every handle to an in-memory database shares its one connection, so there is nothing
+            // to read on beside it.
+            return Err(mlua::Error::RuntimeError(
+                "concurrent_reads is not supported for in-memory databases".to_string(),
+            ));
         }
+        let memory = matches!(config.kind, LuaDatabaseKind::Memory);
+        let db = config.build().await?;
+        let shared = if memory {
+            Some(db.connect().into_sql_err()?)
+        } else {
+            None
+        };
 
-        self.connect_impl(cb)
-    }
-
-    pub fn create((config, cb): (LuaDatabaseConfig, OwnedFunction)) -> mlua::Result<()> {
-        Self::create_impl((config, cb))
-    }
-
-    pub fn create_sync(config: LuaDatabaseConfig) -> mlua::Result<LuaDatabase> {
-        let rt = tokio::runtime::Runtime::new()?;
-        rt.block_on(async {
-            let db = match config.kind {
-                LuaDatabaseKind::Remote => {
-                    let LuaDatabaseConfig {
-                        url: Some(url),
-                        token: Some(token),
-                        ..
-                    } = config
-                    else {
-                        return Err(mlua::Error::RuntimeError(
-                            "url and token must be provided".to_string(),
-                        ));
-                    };
-
-                    libsql::Builder::new_remote(url, token)
-                        .build()
-                        .await
-                        .into_lua_err()
-                }
-                LuaDatabaseKind::Local => {
-                    let Some(path) = config.url else {
-                        return Err(mlua::Error::RuntimeError(
-                            "path must be provided".to_string(),
-                        ));
-                    };
-
-                    libsql::Builder::new_local(path)
-                        .build()
-                        .await
-                        .into_lua_err()
+        let db = LuaDatabase::new(db, timeout, pool)
+            .with_concurrent_reads(concurrent_reads)
+            .with_types(types);
+        mlua::Result::Ok(match shared {
+            Some(conn) => db.with_shared_connection(conn),
+            None => db,
+        })
+    }
+
+    /// Pulls new frames from the primary into a replica.
+    #[lua_method(async, sync)]
+    pub async fn sync(&self, cb: Option<OwnedFunction>) -> mlua::Result<LuaSyncInfo> {
+        let db = self.db.read().await;
+        let frame_no = db
+            .as_ref()
+            .ok_or_else(closed)?
+            .sync()
+            .await
+            .into_sql_err()?;
+
+        mlua::Result::Ok(LuaSyncInfo { frame_no })
+    }
+
+    /// Checks a connection out of the pool and runs `f(conn)` in a new coroutine, where async
+    /// methods can be called without callbacks. The connection goes back to the pool once `f`
+    /// returns, and the call resolves to `f`'s first return value.
+    pub fn with_connection<'lua>(
+        &self,
+        lua: &'lua Lua,
+        (f, cb): (OwnedFunction, Option<OwnedFunction>),
+    ) -> Pending<'lua> {
+        let checkout =
+            match crate::task::start(lua, self.timeout, Arc::clone(&self.pool).checkout()) {
+                Ok(checkout) => checkout,
+                Err(err) => return Pending::ready(Err(err)),
+            };
+
+        let result = LuaFuture::default();
+        let timeout = self.timeout;
+        let types = self.types;
+        let listened = checkout.listen(lua, {
+            let result = result.clone();
+            Box::new(move |lua, outcome| {
+                let lease = match outcome.and_then(|lease| Lease::from_lua(lease, lua)) {
+                    Ok(lease) => lease,
+                    Err(err) => return result.settle(lua, Err(err)),
+                };
+
+                let conn = lease.connection(timeout, types);
+                match LuaFuture::spawn_with(lua, f.to_ref(), conn) {
+                    // The lease is dropped, returning the connection, once `f` is done.
+                    Ok(run) => run.listen(
+                        lua,
+                        Box::new(move |lua, outcome| {
+                            drop(lease);
+                            result.settle(lua, outcome)
+                        }),
+                    ),
+                    Err(err) => result.settle(lua, Err(err)),
                 }
-                LuaDatabaseKind::Memory => libsql::Builder::new_local(":memory:")
-                    .build()
-                    .await
-                    .into_lua_err(),
-            }?;
+            })
+        });
+        if let Err(err) = listened {
+            return Pending::ready(Err(err));
+        }
 
-            mlua::Result::Ok(LuaDatabase::new(db))
-        })
+        crate::task::deliver(lua, result, cb)
+    }
+
+    /// Reads the schema on a connection from the pool. See `LuaConnection::schema`.
+    #[lua_method(async, sync)]
+    pub async fn schema(&self, cb: Option<OwnedFunction>) -> mlua::Result<Schema> {
+        let lease = Arc::clone(&self.pool).checkout().await?;
+        let conn = lease.conn().lock().await;
+        let conn = conn.as_ref().ok_or_else(closed)?;
+
+        let schema = Schema::read(conn).await?;
+
+        mlua::Result::Ok(schema)
+    }
+
+    /// Closes the database and the idle connections in its pool, once the calls running on it are
+    /// done. Connections opened from it stay open until they are closed themselves.
+    #[lua_method(async, sync)]
+    pub async fn close(&self, cb: Option<OwnedFunction>) -> mlua::Result<()> {
+        self.pool.close();
+        self.db.write().await.take();
+
+        mlua::Result::Ok(())
+    }
+
+    /// Connections in the pool: `max_size`, `idle`, `in_use` and `created` since the database was
+    /// opened.
+    #[lua_method]
+    pub fn pool_stats(&self) -> mlua::Result<PoolStats> {
+        Ok(self.pool.stats())
+    }
+}
+
+/// Result of syncing a replica.
+#[derive(Debug, Clone)]
+pub struct LuaSyncInfo {
+    /// Replication index the replica is at after the sync, if any frames have been applied.
+    frame_no: Option<u64>,
+}
+
+impl IntoLua<'_> for LuaSyncInfo {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value<'_>> {
+        let info = lua.create_table()?;
+        info.set("frame_no", self.frame_no)?;
+        Ok(mlua::Value::Table(info))
     }
 }
 
@@ -156,7 +329,86 @@ impl UserData for LuaDatabase {
     fn add_fields<'lua, F: mlua::prelude::LuaUserDataFields<'lua, Self>>(_fields: &mut F) {}
 
     fn add_methods<'lua, M: mlua::prelude::LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("connect", Self::connect.wrap());
-        methods.add_method("connect_sync", Self::connect.wrap());
+        Self::add_lua_methods(methods);
+
+        methods.add_async_method("with_connection", Self::with_connection.wrap_async());
+
+        methods.add_meta_method("__close", Self::close_sync.wrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::ParamsList;
+
+    fn memory() -> LuaDatabase {
+        let config = serde_json::from_value(serde_json::json!({ "kind": "memory" })).unwrap();
+        LuaDatabase::create_sync(config).unwrap()
+    }
+
+    fn has_table(conn: &LuaConnection) -> bool {
+        let params = ParamsList::from(libsql::params::Params::None);
+        conn.query_sync(("SELECT x FROM t".to_string(), params))
+            .is_ok_and(|rows| rows.next_sync().unwrap().is_some())
+    }
+
+    #[test]
+    fn memory_databases_share_one_connection() {
+        let db = memory();
+        db.connect_sync()
+            .unwrap()
+            .execute_batch_sync("CREATE TABLE t (x); INSERT INTO t VALUES (1);".to_string())
+            .unwrap();
+
+        assert!(has_table(&db.connect_sync().unwrap()));
+
+        let lease = crate::runtime::block_on(Arc::clone(&db.pool).checkout()).unwrap();
+        assert!(has_table(&lease.connection(None, TypeOptions::default())));
+        drop(lease);
+
+        let lease = crate::runtime::block_on(Arc::clone(&db.pool).checkout()).unwrap();
+        assert!(has_table(&lease.connection(None, TypeOptions::default())));
+    }
+
+    #[test]
+    fn closing_a_memory_handle_keeps_the_database() {
+        let db = memory();
+        let closed = db.connect_sync().unwrap();
+        closed
+            .execute_batch_sync("CREATE TABLE t (x); INSERT INTO t VALUES (1);".to_string())
+            .unwrap();
+        let with_timeout = closed.with_timeout(Some(1000)).unwrap();
+        closed.close_sync().unwrap();
+
+        assert!(!has_table(&closed));
+        assert!(!has_table(&with_timeout));
+        assert!(has_table(&db.connect_sync().unwrap()));
+    }
+
+    #[test]
+    fn memory_handles_wait_for_each_others_rollbacks() {
+        let db = memory();
+        let first = db.connect_sync().unwrap();
+        let second = db.connect_sync().unwrap();
+        first
+            .execute_batch_sync("CREATE TABLE t (x)".to_string())
+            .unwrap();
+
+        for _ in 0..50 {
+            let tx = first.transaction_sync(None).unwrap();
+            tx.execute_sync((
+                "INSERT INTO t VALUES (1)".to_string(),
+                ParamsList::from(libsql::params::Params::None),
+            ))
+            .unwrap();
+            drop(tx);
+
+            // Fails with "cannot start a transaction within a transaction" if the BEGIN gets to
+            // the connection before the ROLLBACK started through the other handle.
+            drop(second.transaction_sync(None).unwrap());
+        }
+
+        assert!(!has_table(&second));
     }
 }
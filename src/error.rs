@@ -0,0 +1,388 @@
+//! `LuaSqlError`, the structured form of the errors libsql returns.
+//!
+//! Every `libsql::Error` is mapped here, so Lua can tell a constraint violation from a dropped
+//! connection without matching on messages. Cancelled and timed out calls are reported the same
+//! way. The error still travels through mlua as an external error, so its message is unchanged
+//! wherever it ends up as a string.
+
+use std::fmt;
+use std::sync::Arc;
+
+use mlua::{MetaMethod, UserData, Value};
+
+use crate::prelude::*;
+
+/// Broad category of a `LuaSqlError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// SQLite rejected the statement, locally or on the server. `code` is set when known.
+    SqliteFailure,
+    /// The remote protocol (Hrana) failed.
+    Hrana,
+    /// The database could not be reached or replicated from.
+    Connection,
+    /// The API was used in a way the database does not support.
+    Misuse,
+    /// A value could not be converted to or from SQL.
+    Conversion,
+    /// The call was aborted with `future:cancel()`.
+    Cancelled,
+    /// The call ran past its timeout.
+    Timeout,
+}
+
+impl ErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::SqliteFailure => "sqlite_failure",
+            ErrorKind::Hrana => "hrana",
+            ErrorKind::Connection => "connection",
+            ErrorKind::Misuse => "misuse",
+            ErrorKind::Conversion => "conversion",
+            ErrorKind::Cancelled => "cancelled",
+            ErrorKind::Timeout => "timeout",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LuaSqlError {
+    kind: ErrorKind,
+    /// Extended SQLite result code.
+    code: Option<i32>,
+    message: String,
+    /// The statement that failed, if the error came from running one.
+    sql: Option<String>,
+    /// The libsql error, unless the call failed before libsql reported anything.
+    source: Option<Arc<libsql::Error>>,
+}
+
+impl LuaSqlError {
+    /// Error of a call aborted with `future:cancel()`.
+    pub fn cancelled() -> Self {
+        LuaSqlError::without_source(ErrorKind::Cancelled, "cancelled")
+    }
+
+    /// Error of a call that ran past its timeout.
+    pub fn timeout() -> Self {
+        LuaSqlError::without_source(ErrorKind::Timeout, "timeout")
+    }
+
+    fn without_source(kind: ErrorKind, message: &str) -> Self {
+        LuaSqlError {
+            kind,
+            code: None,
+            message: message.to_owned(),
+            sql: None,
+            source: None,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn code(&self) -> Option<i32> {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn sql(&self) -> Option<&str> {
+        self.sql.as_deref()
+    }
+
+    pub fn with_sql(mut self, sql: &str) -> Self {
+        self.sql = Some(sql.to_owned());
+        self
+    }
+
+    /// Finds the `LuaSqlError` behind `err`, looking through errors re-raised by Lua callbacks.
+    pub fn find(err: &mlua::Error) -> Option<&LuaSqlError> {
+        match err {
+            mlua::Error::CallbackError { cause, .. } => LuaSqlError::find(cause),
+            err => err.downcast_ref(),
+        }
+    }
+
+    /// Converts `err` for handing to Lua: a `LuaSqlError` userdata if it is one, its message
+    /// otherwise.
+    pub fn to_lua_value<'lua>(lua: &'lua Lua, err: &mlua::Error) -> mlua::Result<Value<'lua>> {
+        match LuaSqlError::find(err) {
+            Some(err) => err.clone().into_lua(lua),
+            None => err.to_string().into_lua(lua),
+        }
+    }
+
+    /// `libsql.error(err)`: the `LuaSqlError` behind an error caught with `pcall`, or `nil`.
+    pub fn from_lua_value(value: Value) -> mlua::Result<Option<LuaSqlError>> {
+        Ok(match value {
+            Value::Error(err) => LuaSqlError::find(&err).cloned(),
+            Value::UserData(ud) => ud.borrow::<LuaSqlError>().ok().map(|err| err.clone()),
+            _ => None,
+        })
+    }
+}
+
+impl From<libsql::Error> for LuaSqlError {
+    fn from(err: libsql::Error) -> Self {
+        use libsql::Error as E;
+
+        let (kind, code, message) = match &err {
+            E::SqliteFailure(code, message) => {
+                (ErrorKind::SqliteFailure, Some(*code), message.clone())
+            }
+            E::RemoteSqliteFailure(_, extended_code, message) => (
+                ErrorKind::SqliteFailure,
+                Some(*extended_code),
+                message.clone(),
+            ),
+            E::Sqlite3SyntaxError(..) | E::Sqlite3ParserError(_) => {
+                (ErrorKind::SqliteFailure, None, err.to_string())
+            }
+            E::Hrana(source) => hrana(&source.to_string())
+                .unwrap_or_else(|| (ErrorKind::Hrana, None, err.to_string())),
+            E::WriteDelegation(_) | E::Bincode(_) => (ErrorKind::Hrana, None, err.to_string()),
+            E::ConnectionFailed(message) => (ErrorKind::Connection, None, message.clone()),
+            E::Replication(_) | E::InvalidTlsConfiguration(_) => {
+                (ErrorKind::Connection, None, err.to_string())
+            }
+            E::Misuse(message) => (ErrorKind::Misuse, None, message.clone()),
+            E::ExecuteReturnedRows
+            | E::QueryReturnedNoRows
+            | E::SyncNotSupported(_)
+            | E::FreezeNotSupported(_)
+            | E::Sqlite3UnsupportedStatement
+            | E::InvalidUTF8Path
+            | E::InvalidParserState(_) => (ErrorKind::Misuse, None, err.to_string()),
+            E::NullValue
+            | E::InvalidColumnName(_)
+            | E::ToSqlConversionFailure(_)
+            | E::ColumnNotFound(_)
+            | E::InvalidColumnIndex
+            | E::InvalidColumnType => (ErrorKind::Conversion, None, err.to_string()),
+        };
+
+        LuaSqlError {
+            kind,
+            code,
+            message,
+            sql: None,
+            source: Some(Arc::new(err)),
+        }
+    }
+}
+
+/// Maps a Hrana error by its message, as libsql keeps the type of these errors private. `None` for
+/// failures of the protocol itself.
+fn hrana(error: &str) -> Option<(ErrorKind, Option<i32>, String)> {
+    let (variant, detail) = error.split_once(": `")?;
+    let detail = detail.strip_suffix('`')?;
+
+    match variant {
+        // `{:?}` of the error the server returned: `Error { message: "...", code: "SQLITE_..." }`.
+        "stream error" => {
+            let (message, code) = detail
+                .strip_prefix("Error { message: \"")?
+                .strip_suffix("\" }")?
+                .rsplit_once("\", code: \"")?;
+            Some((
+                ErrorKind::SqliteFailure,
+                sqlite_code(code),
+                unescape(message),
+            ))
+        }
+        // `error at step N: (error code: SQLITE_...) `...``, from queries read through a cursor.
+        "cursor error" => {
+            let (code, message) = detail.split_once("(error code: ")?.1.split_once(") `")?;
+            let message = message.strip_suffix('`')?;
+            Some((
+                ErrorKind::SqliteFailure,
+                sqlite_code(code),
+                message.to_owned(),
+            ))
+        }
+        // A statement of a batch failed. libsql only keeps its message.
+        "api error" => Some((ErrorKind::SqliteFailure, None, detail.to_owned())),
+        "http error" | "stream closed" => Some((ErrorKind::Connection, None, detail.to_owned())),
+        _ => None,
+    }
+}
+
+/// The result code Hrana servers report by name.
+fn sqlite_code(name: &str) -> Option<i32> {
+    use libsql::ffi as c;
+
+    Some(match name {
+        "SQLITE_ERROR" => c::SQLITE_ERROR,
+        "SQLITE_INTERNAL" => c::SQLITE_INTERNAL,
+        "SQLITE_PERM" => c::SQLITE_PERM,
+        "SQLITE_ABORT" => c::SQLITE_ABORT,
+        "SQLITE_BUSY" => c::SQLITE_BUSY,
+        "SQLITE_LOCKED" => c::SQLITE_LOCKED,
+        "SQLITE_NOMEM" => c::SQLITE_NOMEM,
+        "SQLITE_READONLY" => c::SQLITE_READONLY,
+        "SQLITE_INTERRUPT" => c::SQLITE_INTERRUPT,
+        "SQLITE_IOERR" => c::SQLITE_IOERR,
+        "SQLITE_CORRUPT" => c::SQLITE_CORRUPT,
+        "SQLITE_NOTFOUND" => c::SQLITE_NOTFOUND,
+        "SQLITE_FULL" => c::SQLITE_FULL,
+        "SQLITE_CANTOPEN" => c::SQLITE_CANTOPEN,
+        "SQLITE_PROTOCOL" => c::SQLITE_PROTOCOL,
+        "SQLITE_EMPTY" => c::SQLITE_EMPTY,
+        "SQLITE_SCHEMA" => c::SQLITE_SCHEMA,
+        "SQLITE_TOOBIG" => c::SQLITE_TOOBIG,
+        "SQLITE_CONSTRAINT" => c::SQLITE_CONSTRAINT,
+        "SQLITE_MISMATCH" => c::SQLITE_MISMATCH,
+        "SQLITE_MISUSE" => c::SQLITE_MISUSE,
+        "SQLITE_NOLFS" => c::SQLITE_NOLFS,
+        "SQLITE_AUTH" => c::SQLITE_AUTH,
+        "SQLITE_FORMAT" => c::SQLITE_FORMAT,
+        "SQLITE_RANGE" => c::SQLITE_RANGE,
+        "SQLITE_NOTADB" => c::SQLITE_NOTADB,
+        "SQLITE_NOTICE" => c::SQLITE_NOTICE,
+        "SQLITE_WARNING" => c::SQLITE_WARNING,
+        "SQLITE_CONSTRAINT_CHECK" => c::SQLITE_CONSTRAINT_CHECK,
+        "SQLITE_CONSTRAINT_COMMITHOOK" => c::SQLITE_CONSTRAINT_COMMITHOOK,
+        "SQLITE_CONSTRAINT_FOREIGNKEY" => c::SQLITE_CONSTRAINT_FOREIGNKEY,
+        "SQLITE_CONSTRAINT_FUNCTION" => c::SQLITE_CONSTRAINT_FUNCTION,
+        "SQLITE_CONSTRAINT_NOTNULL" => c::SQLITE_CONSTRAINT_NOTNULL,
+        "SQLITE_CONSTRAINT_PRIMARYKEY" => c::SQLITE_CONSTRAINT_PRIMARYKEY,
+        "SQLITE_CONSTRAINT_TRIGGER" => c::SQLITE_CONSTRAINT_TRIGGER,
+        "SQLITE_CONSTRAINT_UNIQUE" => c::SQLITE_CONSTRAINT_UNIQUE,
+        "SQLITE_CONSTRAINT_VTAB" => c::SQLITE_CONSTRAINT_VTAB,
+        "SQLITE_CONSTRAINT_ROWID" => c::SQLITE_CONSTRAINT_ROWID,
+        _ => return None,
+    })
+}
+
+/// Undoes the escaping of a string formatted with `{:?}`.
+fn unescape(debug: &str) -> String {
+    let mut unescaped = String::with_capacity(debug.len());
+    let mut chars = debug.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some('0') => unescaped.push('\0'),
+            Some('u') => {
+                let hex: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                unescaped.extend(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32));
+            }
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+impl fmt::Display for LuaSqlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(source) => source.fmt(f),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for LuaSqlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl From<LuaSqlError> for mlua::Error {
+    fn from(err: LuaSqlError) -> Self {
+        mlua::Error::external(err)
+    }
+}
+
+/// `into_lua_err` for libsql results, keeping the error structured.
+pub trait SqlResultExt<T> {
+    fn into_sql_err(self) -> mlua::Result<T>;
+
+    /// Like `into_sql_err`, recording `sql` as the statement that failed.
+    fn with_sql(self, sql: &str) -> mlua::Result<T>;
+}
+
+impl<T> SqlResultExt<T> for Result<T, libsql::Error> {
+    fn into_sql_err(self) -> mlua::Result<T> {
+        self.map_err(|err| LuaSqlError::from(err).into())
+    }
+
+    fn with_sql(self, sql: &str) -> mlua::Result<T> {
+        self.map_err(|err| LuaSqlError::from(err).with_sql(sql).into())
+    }
+}
+
+impl UserData for LuaSqlError {
+    fn add_fields<'lua, F: mlua::prelude::LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("kind", |_lua, this| Ok(this.kind.as_str()));
+        fields.add_field_method_get("code", |_lua, this| Ok(this.code));
+        fields.add_field_method_get("message", |_lua, this| Ok(this.message.clone()));
+        fields.add_field_method_get("sql", |_lua, this| Ok(this.sql.clone()));
+    }
+
+    fn add_methods<'lua, M: mlua::prelude::LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::ToString, |_lua, this, ()| Ok(this.to_string()));
+        // Callbacks used to get error strings, so keep `"prefix: " .. err` working.
+        methods.add_meta_function(
+            MetaMethod::Concat,
+            |lua, (lhs, rhs): (Value, Value)| -> mlua::Result<String> {
+                let tostring: mlua::Function = lua.globals().get("tostring")?;
+                Ok(format!(
+                    "{}{}",
+                    tostring.call::<_, String>(lhs)?,
+                    tostring.call::<_, String>(rhs)?
+                ))
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hrana(message: &str) -> LuaSqlError {
+        LuaSqlError::from(libsql::Error::Hrana(message.into()))
+    }
+
+    #[test]
+    fn hrana_stream_errors_carry_the_sqlite_code() {
+        let err = from_hrana(concat!(
+            r#"stream error: `Error { message: "UNIQUE constraint failed: t.\"x\"", "#,
+            r#"code: "SQLITE_CONSTRAINT_UNIQUE" }`"#,
+        ));
+        assert_eq!(err.kind(), ErrorKind::SqliteFailure);
+        assert_eq!(err.code(), Some(2067));
+        assert_eq!(err.message(), r#"UNIQUE constraint failed: t."x""#);
+
+        let err = from_hrana(
+            "cursor error: `error at step 0: (error code: SQLITE_ERROR) `no such table: t``",
+        );
+        assert_eq!(err.kind(), ErrorKind::SqliteFailure);
+        assert_eq!(err.code(), Some(1));
+        assert_eq!(err.message(), "no such table: t");
+    }
+
+    #[test]
+    fn hrana_transport_errors_are_connection_errors() {
+        let err = from_hrana("http error: `error sending request`");
+        assert_eq!(err.kind(), ErrorKind::Connection);
+        assert_eq!(err.message(), "error sending request");
+
+        let err = from_hrana("unexpected response: `foo`");
+        assert_eq!(err.kind(), ErrorKind::Hrana);
+        assert_eq!(err.code(), None);
+    }
+}
@@ -0,0 +1,339 @@
+//! `LuaFuture`, the handle async methods return for a result that is still on its way.
+//!
+//! Futures only live on the Lua thread: they are settled from the libuv callback that delivers a
+//! task's result, and everything waiting on them (callbacks, coroutines, chained futures) runs
+//! there too.
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use mlua::{OwnedFunction, OwnedThread, RegistryKey, ThreadStatus, UserData, Value};
+
+use crate::error::LuaSqlError;
+use crate::prelude::*;
+
+pub(crate) type Listener =
+    Box<dyn for<'lua> FnOnce(&'lua Lua, mlua::Result<Value<'lua>>) -> mlua::Result<()>>;
+
+/// Runs `f` in a new coroutine, settling the future it is given with what `pcall(f, ...)` returns.
+const SPAWN: &str = r#"
+local f, settle = ...
+return coroutine.create(function(...)
+    settle(pcall(f, ...))
+end)
+"#;
+
+/// The eventual result of an async call.
+#[derive(Clone, Default, mlua::FromLua)]
+pub struct LuaFuture(Rc<RefCell<FutureState>>);
+
+#[derive(Default)]
+struct FutureState {
+    /// `Some` once the future has settled.
+    outcome: Option<mlua::Result<RegistryKey>>,
+    /// Run once, when the future settles.
+    listeners: Vec<Listener>,
+    /// Aborts the task producing the result, if there is one still running.
+    abort: Option<tokio::task::AbortHandle>,
+}
+
+impl LuaFuture {
+    pub(crate) fn set_abort(&self, abort: tokio::task::AbortHandle) {
+        self.0.borrow_mut().abort = Some(abort);
+    }
+
+    /// The settled result, or `None` while the future is pending.
+    fn outcome<'lua>(&self, lua: &'lua Lua) -> Option<mlua::Result<Value<'lua>>> {
+        match self.0.borrow().outcome.as_ref()? {
+            Ok(key) => Some(lua.registry_value(key)),
+            Err(err) => Some(Err(err.clone())),
+        }
+    }
+
+    /// Settles the future and runs its listeners. Does nothing if it has already settled.
+    pub(crate) fn settle<'lua>(
+        &self,
+        lua: &'lua Lua,
+        outcome: mlua::Result<Value<'lua>>,
+    ) -> mlua::Result<()> {
+        let listeners = {
+            let mut state = self.0.borrow_mut();
+            if state.outcome.is_some() {
+                return Ok(());
+            }
+            state.outcome = Some(match &outcome {
+                Ok(value) => Ok(lua.create_registry_value(value.clone())?),
+                Err(err) => Err(err.clone()),
+            });
+            state.abort = None;
+            std::mem::take(&mut state.listeners)
+        };
+
+        // A failing listener must not keep the others from running.
+        for listener in listeners {
+            if let Err(err) = listener(lua, outcome.clone()) {
+                crate::task::report(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs `listener` once the future settles, or straight away if it already has.
+    pub(crate) fn listen(&self, lua: &Lua, listener: Listener) -> mlua::Result<()> {
+        match self.outcome(lua) {
+            Some(outcome) => listener(lua, outcome),
+            None => {
+                self.0.borrow_mut().listeners.push(listener);
+                Ok(())
+            }
+        }
+    }
+
+    /// Calls `cb` Node-style, as `cb(err, result)`, once the future settles.
+    pub(crate) fn listen_callback(&self, lua: &Lua, cb: OwnedFunction) -> mlua::Result<()> {
+        self.listen(
+            lua,
+            Box::new(move |lua, outcome| match outcome {
+                Ok(value) => cb.call((Value::Nil, value)),
+                Err(err) => cb.call((LuaSqlError::to_lua_value(lua, &err)?, Value::Nil)),
+            }),
+        )
+    }
+
+    /// Settles with `result`, or, if it is a future, with whatever that future settles with.
+    fn follow<'lua>(&self, lua: &'lua Lua, result: mlua::Result<Value<'lua>>) -> mlua::Result<()> {
+        match result {
+            Ok(Value::UserData(ud)) if ud.is::<LuaFuture>() => {
+                let inner = ud.borrow::<LuaFuture>()?.clone();
+                let next = self.clone();
+                inner.listen(lua, Box::new(move |lua, outcome| next.settle(lua, outcome)))
+            }
+            result => self.settle(lua, result),
+        }
+    }
+
+    /// Yields the current coroutine until the future settles, then returns its value or raises
+    /// its error.
+    pub fn r#await<'lua>(&self, lua: &'lua Lua, _: ()) -> Pending<'lua> {
+        match crate::task::running_coroutine(lua) {
+            Ok(Some(thread)) => Pending::awaiting(lua, self.clone(), thread.into_owned()),
+            Ok(None) => Pending::ready(Err(mlua::Error::RuntimeError(
+                "await must be called from a coroutine, use wait instead".to_string(),
+            ))),
+            Err(err) => Pending::ready(Err(err)),
+        }
+    }
+
+    /// Runs the event loop until the future settles, for at most `timeout_ms` (no limit by
+    /// default), then returns its value or raises its error.
+    pub fn wait<'lua>(&self, lua: &'lua Lua, timeout_ms: Option<u32>) -> mlua::Result<Value<'lua>> {
+        if self.outcome(lua).is_none() {
+            let vim_wait: mlua::Function =
+                lua.globals().get::<_, mlua::Table>("vim")?.get("wait")?;
+            let done = lua.create_function({
+                let this = self.clone();
+                move |_lua, ()| this.is_done()
+            })?;
+            // `vim.wait` takes a signed 32-bit timeout. Check often, results arrive between polls.
+            let timeout_ms = timeout_ms.unwrap_or(i32::MAX as u32).min(i32::MAX as u32);
+            vim_wait.call::<_, ()>((timeout_ms, done, 1))?;
+        }
+
+        self.outcome(lua).unwrap_or_else(|| {
+            Err(mlua::Error::RuntimeError(
+                "timed out waiting for the future".to_string(),
+            ))
+        })
+    }
+
+    /// Returns a future for `f(value)`. Errors skip `f` and pass through. If `f` returns a future,
+    /// the new one settles along with it.
+    pub fn and_then(&self, lua: &Lua, f: OwnedFunction) -> mlua::Result<LuaFuture> {
+        let next = LuaFuture::default();
+        self.listen(lua, {
+            let next = next.clone();
+            Box::new(move |lua, outcome| match outcome {
+                Ok(value) => next.follow(lua, f.call(value)),
+                Err(err) => next.settle(lua, Err(err)),
+            })
+        })?;
+        Ok(next)
+    }
+
+    /// Returns a future that recovers from an error with `f(err)`. Values pass through untouched.
+    pub fn catch(&self, lua: &Lua, f: OwnedFunction) -> mlua::Result<LuaFuture> {
+        let next = LuaFuture::default();
+        self.listen(lua, {
+            let next = next.clone();
+            Box::new(move |lua, outcome| match outcome {
+                Ok(value) => next.settle(lua, Ok(value)),
+                Err(err) => next.follow(lua, f.call(LuaSqlError::to_lua_value(lua, &err)?)),
+            })
+        })?;
+        Ok(next)
+    }
+
+    pub fn is_done(&self) -> mlua::Result<bool> {
+        Ok(self.0.borrow().outcome.is_some())
+    }
+
+    /// Aborts the task behind the future and fails it with a "cancelled" error. Returns `false`
+    /// if the future had already settled.
+    pub fn cancel(&self, lua: &Lua, _: ()) -> mlua::Result<bool> {
+        if self.0.borrow().outcome.is_some() {
+            return Ok(false);
+        }
+        if let Some(abort) = self.0.borrow_mut().abort.take() {
+            abort.abort();
+        }
+
+        self.settle(lua, Err(LuaSqlError::cancelled().into()))?;
+        Ok(true)
+    }
+
+    /// Returns a future for the list of values of `futures`, in order. Fails with the first error.
+    pub fn join(lua: &Lua, futures: Vec<LuaFuture>) -> mlua::Result<LuaFuture> {
+        let all = LuaFuture::default();
+        let values = lua.create_table_with_capacity(futures.len(), 0)?;
+        if futures.is_empty() {
+            all.settle(lua, Ok(Value::Table(values)))?;
+            return Ok(all);
+        }
+
+        let values = values.into_owned();
+        let remaining = Rc::new(Cell::new(futures.len()));
+        for (i, future) in futures.into_iter().enumerate() {
+            let all = all.clone();
+            let values = values.clone();
+            let remaining = Rc::clone(&remaining);
+            future.listen(
+                lua,
+                Box::new(move |lua, outcome| match outcome {
+                    Ok(value) => {
+                        let values = values.to_ref();
+                        values.raw_set(i + 1, value)?;
+                        remaining.set(remaining.get() - 1);
+                        if remaining.get() > 0 {
+                            return Ok(());
+                        }
+                        all.settle(lua, Ok(Value::Table(values)))
+                    }
+                    Err(err) => all.settle(lua, Err(err)),
+                }),
+            )?;
+        }
+
+        Ok(all)
+    }
+
+    /// Runs `f` in a new coroutine, where async methods can be called without callbacks, and
+    /// returns a future for its first return value.
+    pub fn spawn(lua: &Lua, f: mlua::Function) -> mlua::Result<LuaFuture> {
+        LuaFuture::spawn_with(lua, f, ())
+    }
+
+    /// Like `spawn`, calling `f` with `args`.
+    pub(crate) fn spawn_with<'lua>(
+        lua: &'lua Lua,
+        f: mlua::Function<'lua>,
+        args: impl IntoLuaMulti<'lua>,
+    ) -> mlua::Result<LuaFuture> {
+        let future = LuaFuture::default();
+        let settle = lua.create_function({
+            let future = future.clone();
+            move |lua, (ok, value): (bool, Value)| {
+                let outcome = match (ok, value) {
+                    (true, value) => Ok(value),
+                    (false, Value::Error(err)) => Err(err),
+                    (false, value) => Err(mlua::Error::RuntimeError(value.to_string()?)),
+                };
+                future.settle(lua, outcome)
+            }
+        })?;
+
+        let thread: mlua::Thread = lua.load(SPAWN).call((f, settle))?;
+        thread.resume::<_, ()>(args)?;
+
+        Ok(future)
+    }
+}
+
+impl UserData for LuaFuture {
+    fn add_fields<'lua, F: mlua::prelude::LuaUserDataFields<'lua, Self>>(_fields: &mut F) {}
+
+    fn add_methods<'lua, M: mlua::prelude::LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_async_method("await", Self::r#await.wrap_async());
+        methods.add_method("wait", Self::wait.wrap());
+        methods.add_method("and_then", Self::and_then.wrap());
+        methods.add_method("catch", Self::catch.wrap());
+        methods.add_method("is_done", Self::is_done.wrap());
+        methods.add_method("cancel", Self::cancel.wrap());
+    }
+}
+
+/// What an async method, or `future:await()`, hands back to mlua.
+///
+/// Resolves straight away unless a coroutine is awaiting a future. In that case it stays pending,
+/// which makes mlua yield the coroutine, until the future settles and resumes it.
+pub enum Pending<'lua> {
+    Ready(Option<mlua::Result<Value<'lua>>>),
+    Awaiting {
+        lua: &'lua Lua,
+        future: LuaFuture,
+        /// Handed to the future on the first poll, to be resumed once it settles.
+        thread: Option<OwnedThread>,
+    },
+}
+
+impl<'lua> Pending<'lua> {
+    pub(crate) fn ready(outcome: mlua::Result<Value<'lua>>) -> Self {
+        Pending::Ready(Some(outcome))
+    }
+
+    pub(crate) fn awaiting(lua: &'lua Lua, future: LuaFuture, thread: OwnedThread) -> Self {
+        Pending::Awaiting {
+            lua,
+            future,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl<'lua> Future for Pending<'lua> {
+    type Output = mlua::Result<Value<'lua>>;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.get_mut() {
+            Pending::Ready(outcome) => Poll::Ready(outcome.take().unwrap_or(Ok(Value::Nil))),
+            Pending::Awaiting {
+                lua,
+                future,
+                thread,
+            } => {
+                let lua: &'lua Lua = lua;
+                if let Some(outcome) = future.outcome(lua) {
+                    return Poll::Ready(outcome);
+                }
+                if let Some(thread) = thread.take() {
+                    if let Err(err) = future.listen(lua, Box::new(move |_, _| resume(thread))) {
+                        return Poll::Ready(Err(err));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Resumes a coroutine waiting on a future. It may have finished or been resumed elsewhere in the
+/// meantime, in which case there is nothing to do.
+fn resume(thread: OwnedThread) -> mlua::Result<()> {
+    if thread.status() != ThreadStatus::Resumable {
+        return Ok(());
+    }
+    thread.resume::<_, ()>(())
+}
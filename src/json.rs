@@ -0,0 +1,376 @@
+//! JSON conversion of Lua values, for binding tables as parameters and decoding result columns.
+//!
+//! Tables bind as JSON text. `libsql.jsonb` produces SQLite's binary JSONB format instead, which
+//! the JSON functions read without parsing (SQLite 3.45 or later).
+
+use std::collections::HashMap;
+
+use libsql_nvim_derive::FromLuaSerde;
+use mlua::serde::LuaSerdeExt;
+use serde_json::Value as Json;
+
+use crate::prelude::*;
+use crate::types::{self, NullMode};
+
+/// Format a result column is decoded from.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub enum Format {
+    /// JSON text, stored as `TEXT` or `BLOB`.
+    #[serde(rename = "json")]
+    Json,
+}
+
+/// Columns to decode, by name.
+#[derive(Debug, Clone)]
+pub struct Decode {
+    pub columns: HashMap<String, Format>,
+    /// What JSON `null`s decode to. Unlike SQL `NULL`s, they default to `vim.NIL`, so they keep
+    /// their place in arrays and their key in objects.
+    pub json_null: NullMode,
+}
+
+impl Default for Decode {
+    fn default() -> Self {
+        Decode {
+            columns: HashMap::new(),
+            json_null: NullMode::Null,
+        }
+    }
+}
+
+impl Decode {
+    /// The decoder for each of `columns`, `None` for those returned as they are.
+    pub fn decoders(&self, columns: &[String]) -> Vec<Option<Decoder>> {
+        columns
+            .iter()
+            .map(|name| {
+                self.columns.get(name).map(|&format| Decoder {
+                    format,
+                    json_null: self.json_null,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Decoder {
+    format: Format,
+    json_null: NullMode,
+}
+
+impl Decoder {
+    /// Decodes the contents of a `TEXT` or `BLOB` value.
+    pub fn decode<'lua>(self, lua: &'lua Lua, bytes: &[u8]) -> mlua::Result<mlua::Value<'lua>> {
+        let json = match self.format {
+            Format::Json => serde_json::from_slice::<Json>(bytes),
+        }
+        .map_err(|err| mlua::Error::RuntimeError(format!("column is not valid JSON: {err}")))?;
+
+        let null = match self.json_null {
+            NullMode::Null => types::vim_nil(lua)?,
+            NullMode::Nil => mlua::Value::Nil,
+        };
+        from_json(lua, json, &null)
+    }
+}
+
+/// Converts `json` the way mlua deserializes it, except that `null`s become `null`.
+fn from_json<'lua>(
+    lua: &'lua Lua,
+    json: Json,
+    null: &mlua::Value<'lua>,
+) -> mlua::Result<mlua::Value<'lua>> {
+    use mlua::Value as V;
+
+    match json {
+        Json::Null => Ok(null.clone()),
+        Json::Bool(b) => Ok(V::Boolean(b)),
+        Json::Number(n) => Ok(match n.as_i64() {
+            Some(i) => V::Integer(i),
+            None => V::Number(n.as_f64().unwrap_or(f64::NAN)),
+        }),
+        Json::String(s) => lua.create_string(s).map(V::String),
+        Json::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, from_json(lua, item, null)?)?;
+            }
+            table.set_metatable(Some(lua.array_metatable()));
+            Ok(V::Table(table))
+        }
+        Json::Object(fields) => {
+            let table = lua.create_table_with_capacity(0, fields.len())?;
+            for (key, value) in fields {
+                table.raw_set(key, from_json(lua, value, null)?)?;
+            }
+            Ok(V::Table(table))
+        }
+    }
+}
+
+/// JSONB element types, from SQLite's `jsonb` format.
+mod element {
+    pub const NULL: u8 = 0;
+    pub const TRUE: u8 = 1;
+    pub const FALSE: u8 = 2;
+    pub const INT: u8 = 3;
+    pub const FLOAT: u8 = 5;
+    /// Text that needs no escaping in JSON.
+    pub const TEXT: u8 = 7;
+    /// Text that must be escaped when written out as JSON.
+    pub const TEXTRAW: u8 = 10;
+    pub const ARRAY: u8 = 11;
+    pub const OBJECT: u8 = 12;
+}
+
+fn unsupported(value: &mlua::Value, reason: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!(
+        "{} cannot be bound as JSON: {reason}",
+        value.type_name()
+    ))
+}
+
+/// Converts `value` the way mlua serializes it, except that `vim.NIL` becomes `null` too.
+fn to_json(
+    lua: &Lua,
+    value: &mlua::Value,
+    visiting: &mut Vec<*const std::ffi::c_void>,
+) -> mlua::Result<Json> {
+    use mlua::Value as V;
+
+    if types::is_null(lua, value)? {
+        return Ok(Json::Null);
+    }
+
+    match value {
+        V::Nil => Ok(Json::Null),
+        V::Boolean(b) => Ok(Json::Bool(*b)),
+        V::Integer(i) => Ok(Json::from(*i)),
+        V::Number(n) if !n.is_finite() => {
+            Err(unsupported(value, "NaN and infinities have no JSON form"))
+        }
+        V::Number(n) => Ok(Json::from(*n)),
+        V::String(s) => Ok(Json::String(s.to_str()?.to_owned())),
+        V::Table(table) => {
+            let pointer = table.to_pointer();
+            if visiting.contains(&pointer) {
+                return Err(unsupported(value, "recursive table detected"));
+            }
+            visiting.push(pointer);
+
+            let len = table.raw_len();
+            let json = if len > 0 || table.get_metatable() == Some(lua.array_metatable()) {
+                // Any other key would be lost in the array.
+                for pair in table.clone().pairs::<mlua::Value, mlua::Value>() {
+                    let (key, _) = pair?;
+                    if !matches!(key, V::Integer(i) if (1..=len as i64).contains(&i)) {
+                        return Err(unsupported(
+                            value,
+                            "a table with a sequence can't have other keys",
+                        ));
+                    }
+                }
+
+                (1..=len)
+                    .map(|i| to_json(lua, &table.raw_get(i)?, visiting))
+                    .collect::<mlua::Result<_>>()
+                    .map(Json::Array)?
+            } else {
+                let mut fields = serde_json::Map::new();
+                for pair in table.clone().pairs::<mlua::Value, mlua::Value>() {
+                    let (key, item) = pair?;
+                    let key = match &key {
+                        V::String(key) => key.to_str()?.to_owned(),
+                        V::Integer(key) => key.to_string(),
+                        key => return Err(unsupported(key, "keys must be strings")),
+                    };
+                    fields.insert(key, to_json(lua, &item, visiting)?);
+                }
+                Json::Object(fields)
+            };
+
+            visiting.pop();
+            Ok(json)
+        }
+        value => Err(unsupported(value, "unsupported value type")),
+    }
+}
+
+/// `value` as JSON text.
+pub fn to_text(lua: &Lua, value: &mlua::Value) -> mlua::Result<String> {
+    Ok(to_json(lua, value, &mut Vec::new())?.to_string())
+}
+
+/// `value` in SQLite's JSONB format.
+pub fn to_jsonb(lua: &Lua, value: &mlua::Value) -> mlua::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    encode(&to_json(lua, value, &mut Vec::new())?, &mut out);
+    Ok(out)
+}
+
+fn encode(json: &Json, out: &mut Vec<u8>) {
+    match json {
+        Json::Null => header(element::NULL, 0, out),
+        Json::Bool(true) => header(element::TRUE, 0, out),
+        Json::Bool(false) => header(element::FALSE, 0, out),
+        Json::Number(n) => {
+            let kind = if n.is_f64() {
+                element::FLOAT
+            } else {
+                element::INT
+            };
+            leaf(kind, n.to_string().as_bytes(), out);
+        }
+        Json::String(s) => {
+            let kind = if s.chars().any(|c| c == '"' || c == '\\' || c < ' ') {
+                element::TEXTRAW
+            } else {
+                element::TEXT
+            };
+            leaf(kind, s.as_bytes(), out);
+        }
+        Json::Array(items) => {
+            let mut payload = Vec::new();
+            for item in items {
+                encode(item, &mut payload);
+            }
+            leaf(element::ARRAY, &payload, out);
+        }
+        Json::Object(fields) => {
+            let mut payload = Vec::new();
+            for (key, value) in fields {
+                encode(&Json::String(key.clone()), &mut payload);
+                encode(value, &mut payload);
+            }
+            leaf(element::OBJECT, &payload, out);
+        }
+    }
+}
+
+fn leaf(kind: u8, payload: &[u8], out: &mut Vec<u8>) {
+    header(kind, payload.len(), out);
+    out.extend_from_slice(payload);
+}
+
+/// The element header: the type in the low nibble, and the payload size either in the high
+/// nibble or in the 1, 2, 4 or 8 big-endian bytes that follow.
+fn header(kind: u8, size: usize, out: &mut Vec<u8>) {
+    match size {
+        0..=11 => out.push((size as u8) << 4 | kind),
+        12..=0xff => out.extend([12 << 4 | kind, size as u8]),
+        0x100..=0xffff => {
+            out.push(13 << 4 | kind);
+            out.extend((size as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(14 << 4 | kind);
+            out.extend((size as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(15 << 4 | kind);
+            out.extend((size as u64).to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luajit2_sys as _;
+
+    use super::*;
+    use serde_json::json;
+
+    // Expected bytes follow https://sqlite.org/jsonb.html. SQLite's `json()` reads each back to
+    // the value encoded.
+
+    fn jsonb(json: Json) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(&json, &mut out);
+        out
+    }
+
+    fn header(size: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        super::header(element::TEXT, size, &mut out);
+        out
+    }
+
+    #[test]
+    fn jsonb_encodes_scalars() {
+        assert_eq!(jsonb(json!(null)), [0x00]);
+        assert_eq!(jsonb(json!(true)), [0x01]);
+        assert_eq!(jsonb(json!(false)), [0x02]);
+        assert_eq!(jsonb(json!(12)), [0x23, b'1', b'2']);
+        assert_eq!(jsonb(json!(-1)), [0x23, b'-', b'1']);
+        assert_eq!(jsonb(json!(1.5)), [0x35, b'1', b'.', b'5']);
+    }
+
+    #[test]
+    fn jsonb_text_that_needs_escaping_is_textraw() {
+        assert_eq!(jsonb(json!("abc")), [0x37, b'a', b'b', b'c']);
+        assert_eq!(jsonb(json!("")), [0x07]);
+        assert_eq!(jsonb(json!("a\"b")), [0x3a, b'a', b'"', b'b']);
+        assert_eq!(jsonb(json!("a\\b")), [0x3a, b'a', b'\\', b'b']);
+        assert_eq!(jsonb(json!("a\nb")), [0x3a, b'a', b'\n', b'b']);
+    }
+
+    #[test]
+    fn jsonb_encodes_containers() {
+        assert_eq!(jsonb(json!([])), [0x0b]);
+        assert_eq!(jsonb(json!({})), [0x0c]);
+        assert_eq!(jsonb(json!([1, "x"])), [0x4b, 0x13, b'1', 0x17, b'x']);
+        assert_eq!(jsonb(json!({ "a": null })), [0x3c, 0x17, b'a', 0x00]);
+    }
+
+    #[test]
+    fn jsonb_header_grows_at_each_size_boundary() {
+        assert_eq!(header(11), [0xb7]);
+        assert_eq!(header(12), [0xc7, 12]);
+        assert_eq!(header(0xff), [0xc7, 0xff]);
+        assert_eq!(header(0x100), [0xd7, 0x01, 0x00]);
+        assert_eq!(header(0xffff), [0xd7, 0xff, 0xff]);
+        assert_eq!(header(0x1_0000), [0xe7, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(header(0xffff_ffff), [0xe7, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(header(0x1_0000_0000), [0xf7, 0, 0, 0, 1, 0, 0, 0, 0]);
+
+        let text = "a".repeat(12);
+        assert_eq!(jsonb(json!(text))[..2], [0xc7, 12]);
+    }
+
+    #[test]
+    fn json_nulls_decode_to_vim_nil() {
+        let lua = Lua::new();
+        lua.load("vim = { NIL = newproxy(false) }").exec().unwrap();
+
+        let decoder = Decoder {
+            format: Format::Json,
+            json_null: NullMode::Null,
+        };
+        let value = decoder.decode(&lua, br#"[null, {"a": null}]"#).unwrap();
+        lua.globals().set("value", value).unwrap();
+
+        let nulls: bool = lua
+            .load("return value[1] == vim.NIL and value[2].a == vim.NIL")
+            .eval()
+            .unwrap();
+        assert!(nulls);
+    }
+
+    #[test]
+    fn tables_that_lose_values_are_rejected() {
+        let lua = Lua::new();
+        lua.load("vim = { NIL = newproxy(false) }").exec().unwrap();
+        let text = |code: &str| to_text(&lua, &lua.load(code).eval().unwrap());
+
+        assert_eq!(text(r#"return { "a", "b" }"#).unwrap(), r#"["a","b"]"#);
+        assert_eq!(text(r#"return { name = "x" }"#).unwrap(), r#"{"name":"x"}"#);
+
+        let err = text(r#"return { "a", name = "x" }"#).unwrap_err();
+        assert!(err.to_string().contains("other keys"), "{err}");
+        for number in ["0/0", "1/0", "-1/0"] {
+            let err = text(&format!("return {{ {number} }}")).unwrap_err();
+            assert!(err.to_string().contains("NaN"), "{err}");
+        }
+    }
+}
@@ -1,9 +1,21 @@
 use std::sync::{atomic::AtomicPtr, Arc};
 
+pub mod blob;
 pub mod conn;
+pub mod datetime;
 pub mod db;
+pub mod error;
+pub mod future;
+pub mod json;
+pub mod pool;
 pub mod rows;
+pub mod runtime;
+pub mod schema;
 pub mod ser;
+pub mod stmt;
+pub mod task;
+pub mod transaction;
+pub mod types;
 pub mod wrap;
 
 #[doc(hidden)]
@@ -30,7 +42,7 @@ pub(crate) mod prelude {
 }
 
 #[mlua::lua_module(name = "libsql_native")]
-pub fn libsql(lua: &mlua::Lua) -> mlua::Result<mlua::Table> {
+pub fn libsql(lua: &mlua::Lua) -> mlua::Result<mlua::Table<'_>> {
     let module = lua.create_table()?;
 
     unsafe {
@@ -51,7 +63,7 @@ pub fn libsql(lua: &mlua::Lua) -> mlua::Result<mlua::Table> {
 
     module.set(
         "new_db",
-        lua.create_function(|_lua, args| db::LuaDatabase::create(args))?,
+        lua.create_async_function(db::LuaDatabase::create)?,
     )?;
 
     module.set(
@@ -59,5 +71,35 @@ pub fn libsql(lua: &mlua::Lua) -> mlua::Result<mlua::Table> {
         lua.create_function(|_lua, args| db::LuaDatabase::create_sync(args))?,
     )?;
 
+    module.set(
+        "blob",
+        lua.create_function(|_lua, data: mlua::String| {
+            Ok(blob::LuaBlob::new(data.as_bytes().to_vec()))
+        })?,
+    )?;
+
+    module.set(
+        "jsonb",
+        lua.create_function(|lua, value: mlua::Value| {
+            Ok(blob::LuaBlob::new(json::to_jsonb(lua, &value)?))
+        })?,
+    )?;
+
+    module.set("datetime", lua.create_function(datetime::format)?)?;
+
+    module.set(
+        "error",
+        lua.create_function(|_lua, err| error::LuaSqlError::from_lua_value(err))?,
+    )?;
+
+    module.set("join", lua.create_function(future::LuaFuture::join)?)?;
+
+    module.set("spawn", lua.create_function(future::LuaFuture::spawn)?)?;
+
+    module.set(
+        "configure_runtime",
+        lua.create_function(|_lua, config| runtime::configure(config))?,
+    )?;
+
     Ok(module)
 }
@@ -0,0 +1,330 @@
+//! Connection pool owned by each `LuaDatabase`, used by `db:with_connection`.
+//!
+//! Connections are opened on demand, up to `max_size` at once, and kept idle between checkouts so
+//! that many short calls (e.g. from autocmds) don't each pay for opening a connection.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use libsql_nvim_derive::FromLuaSerde;
+use mlua::serde::LuaSerdeExt;
+use mlua::{FromLua, IntoLua};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::conn::{ConnectionState, LuaConnection, SharedConnection};
+use crate::db::SharedDatabase;
+use crate::error::SqlResultExt;
+use crate::prelude::*;
+use crate::types::TypeOptions;
+
+const DEFAULT_MAX_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub struct PoolConfig {
+    /// Most connections open at once. Defaults to 8.
+    max_size: Option<usize>,
+    /// Milliseconds a connection may stay idle before it is closed. Defaults to no limit.
+    idle_timeout_ms: Option<u64>,
+    /// Whether to run `SELECT 1` on an idle connection before handing it out. Defaults to false.
+    health_check: Option<bool>,
+}
+
+impl PoolConfig {
+    pub(crate) fn validate(&self) -> mlua::Result<()> {
+        if self.max_size == Some(0) {
+            return Err(mlua::Error::RuntimeError(
+                "pool.max_size must be greater than 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct Pool {
+    db: SharedDatabase,
+    max_size: usize,
+    idle_timeout: Option<Duration>,
+    health_check: bool,
+    idle: Mutex<Vec<IdleConnection>>,
+    /// One permit per connection that may be checked out.
+    permits: Arc<Semaphore>,
+    created: AtomicU64,
+    /// The one connection of a pool made with `Pool::single`.
+    shared: Option<SharedConnection>,
+}
+
+struct IdleConnection {
+    conn: SharedConnection,
+    since: Instant,
+}
+
+impl IdleConnection {
+    fn expired(&self, idle_timeout: Option<Duration>) -> bool {
+        idle_timeout.is_some_and(|timeout| self.since.elapsed() >= timeout)
+    }
+}
+
+impl Pool {
+    pub fn new(db: SharedDatabase, config: PoolConfig) -> Self {
+        let max_size = config.max_size.unwrap_or(DEFAULT_MAX_SIZE);
+        Pool {
+            db,
+            max_size,
+            idle_timeout: config.idle_timeout_ms.map(Duration::from_millis),
+            health_check: config.health_check.unwrap_or(false),
+            idle: Mutex::new(Vec::new()),
+            permits: Arc::new(Semaphore::new(max_size)),
+            created: AtomicU64::new(0),
+            shared: None,
+        }
+    }
+
+    /// A pool that hands out `conn` to one user at a time and never opens connections, for
+    /// in-memory databases, where every new connection opens an empty database of its own.
+    pub fn single(db: SharedDatabase, conn: SharedConnection) -> Self {
+        Pool {
+            shared: Some(conn),
+            ..Pool::new(
+                db,
+                PoolConfig {
+                    max_size: Some(1),
+                    ..PoolConfig::default()
+                },
+            )
+        }
+    }
+
+    fn idle(&self) -> std::sync::MutexGuard<'_, Vec<IdleConnection>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits for a free slot, then hands out an idle connection or opens a new one.
+    pub async fn checkout(self: Arc<Self>) -> mlua::Result<Lease> {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .map_err(|_| mlua::Error::RuntimeError("connection pool is closed".to_string()))?;
+
+        if let Some(conn) = &self.shared {
+            let conn = Arc::clone(conn);
+            return Ok(Lease::new(self, conn, permit));
+        }
+
+        loop {
+            // Most recently used first, so connections past the idle timeout pile up at the front.
+            let Some(idle) = self.idle().pop() else {
+                break;
+            };
+            if idle.expired(self.idle_timeout) {
+                continue;
+            }
+            if self.health_check && !healthy(&idle.conn).await {
+                continue;
+            }
+            return Ok(Lease::new(self, idle.conn, permit));
+        }
+
+        let conn = {
+            let db = self.db.read().await;
+            let db = db.as_ref().ok_or_else(crate::db::closed)?;
+            db.connect().into_sql_err()?
+        };
+        self.created.fetch_add(1, Ordering::Relaxed);
+
+        Ok(Lease::new(self, ConnectionState::new(conn), permit))
+    }
+
+    /// Takes a connection back, unless it was left in a state the next user shouldn't inherit.
+    fn checkin(&self, conn: SharedConnection) {
+        if self.shared.is_some() {
+            return;
+        }
+
+        let reusable = !self.permits.is_closed()
+            && match conn.try_read() {
+                // Closed, still running a call, or in the middle of a transaction.
+                Some(guard) => guard
+                    .as_ref()
+                    .is_some_and(libsql::Connection::is_autocommit),
+                None => false,
+            };
+        if !reusable {
+            return;
+        }
+
+        self.prune();
+        self.idle().push(IdleConnection {
+            conn,
+            since: Instant::now(),
+        });
+    }
+
+    /// Closes idle connections as they pass the idle timeout, rather than when the pool is next
+    /// used. Stops once the pool is closed or dropped.
+    pub fn spawn_reaper(self: &Arc<Self>) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        let Ok(rt) = crate::runtime::get() else {
+            return;
+        };
+
+        let pool = Arc::downgrade(self);
+        rt.spawn(async move {
+            let mut next = Instant::now() + idle_timeout;
+            loop {
+                tokio::time::sleep_until(next.into()).await;
+                let Some(pool) = pool.upgrade() else {
+                    break;
+                };
+                if pool.permits.is_closed() {
+                    break;
+                }
+                next = pool
+                    .prune()
+                    .unwrap_or_else(|| Instant::now() + idle_timeout);
+            }
+        });
+    }
+
+    /// Closes the idle connections past the idle timeout. Returns when the next one expires.
+    fn prune(&self) -> Option<Instant> {
+        let idle_timeout = self.idle_timeout?;
+        let mut idle = self.idle();
+        idle.retain(|idle| !idle.expired(Some(idle_timeout)));
+        idle.iter().map(|idle| idle.since + idle_timeout).min()
+    }
+
+    /// Closes the idle connections and fails checkouts from now on. Connections that are checked
+    /// out are closed when they are returned.
+    pub fn close(&self) {
+        self.permits.close();
+        self.idle().clear();
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let idle = if self.shared.is_some() {
+            self.permits.available_permits()
+        } else {
+            self.prune();
+            self.idle().len()
+        };
+
+        PoolStats {
+            max_size: self.max_size,
+            idle,
+            in_use: self.max_size - self.permits.available_permits(),
+            created: self.created.load(Ordering::Relaxed),
+        }
+    }
+}
+
+async fn healthy(conn: &ConnectionState) -> bool {
+    match conn.lock().await.as_ref() {
+        Some(conn) => conn.query("SELECT 1", ()).await.is_ok(),
+        None => false,
+    }
+}
+
+/// A connection checked out of a `Pool`, returned to it when released or dropped.
+pub struct Lease {
+    pool: Arc<Pool>,
+    conn: SharedConnection,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Lease {
+    fn new(pool: Arc<Pool>, conn: SharedConnection, permit: OwnedSemaphorePermit) -> Self {
+        Lease {
+            pool,
+            conn,
+            _permit: permit,
+        }
+    }
+
+    pub(crate) fn conn(&self) -> &ConnectionState {
+        &self.conn
+    }
+
+    pub fn connection(&self, timeout: Option<Duration>, types: TypeOptions) -> LuaConnection {
+        LuaConnection::new(Arc::clone(&self.conn), timeout, types)
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool.checkin(Arc::clone(&self.conn));
+    }
+}
+
+// Only passed through Lua on its way from the runtime to `with_connection`.
+impl UserData for Lease {}
+
+impl FromLua<'_> for Lease {
+    fn from_lua(value: mlua::Value<'_>, _lua: &Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::UserData(ud) => ud.take(),
+            _ => Err(mlua::Error::RuntimeError(
+                "expected a connection lease".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    pub(crate) max_size: usize,
+    pub(crate) idle: usize,
+    pub(crate) in_use: usize,
+    /// Connections opened since the pool was created.
+    pub(crate) created: u64,
+}
+
+impl IntoLua<'_> for PoolStats {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value<'_>> {
+        let stats = lua.create_table()?;
+        stats.set("max_size", self.max_size)?;
+        stats.set("idle", self.idle)?;
+        stats.set("in_use", self.in_use)?;
+        stats.set("created", self.created)?;
+        Ok(mlua::Value::Table(stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::RwLock;
+
+    use super::*;
+
+    #[test]
+    fn idle_connections_close_without_further_use() {
+        let pool = crate::runtime::block_on(async {
+            let db = libsql::Builder::new_local(":memory:")
+                .build()
+                .await
+                .into_sql_err()?;
+            let config = PoolConfig {
+                idle_timeout_ms: Some(100),
+                ..PoolConfig::default()
+            };
+            let pool = Arc::new(Pool::new(Arc::new(RwLock::new(Some(db))), config));
+            pool.spawn_reaper();
+            drop(Arc::clone(&pool).checkout().await?);
+
+            Ok(pool)
+        })
+        .unwrap();
+        assert_eq!(pool.idle().len(), 1);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !pool.idle().is_empty() {
+            assert!(
+                Instant::now() < deadline,
+                "idle connection was never closed"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -1,180 +1,364 @@
-use libsql_nvim_derive::luv_async;
-use mlua::{ExternalResult, IntoLua, OwnedFunction};
+use libsql_nvim_derive::{lua_methods, luv_async, FromLuaSerde};
+use mlua::serde::LuaSerdeExt;
+use mlua::{FromLua, FromLuaMulti, IntoLua, MetaMethod, OwnedFunction};
+use std::collections::HashMap;
 use std::sync::{Arc, OnceLock};
 use tokio::sync::RwLock;
 
-use crate::prelude::*;
+use crate::conn::ParamsList;
+use crate::error::SqlResultExt;
+use crate::future::Pending;
+use crate::json::{Decode, Decoder, Format};
+use crate::types::{declared_types, Declared, NullMode, TypeOptions};
+use crate::{blob::LuaBlob, prelude::*};
+
+/// Shape of each row produced by `collect`, `to_table` and `to_list`.
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub enum RowShape {
+    /// Keyed by column name.
+    #[default]
+    #[serde(rename = "map")]
+    Map,
+    /// Positional, in column order.
+    #[serde(rename = "list")]
+    List,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub struct CollectOptions {
+    /// Maximum number of rows to fetch. Defaults to all remaining rows.
+    limit: Option<usize>,
+    #[serde(rename = "as", default)]
+    shape: RowShape,
+    /// Columns to decode, by name, in addition to those the rows were queried with.
+    #[serde(default)]
+    decode: HashMap<String, Format>,
+    /// What JSON nulls decode to. Defaults to the setting the rows were queried with.
+    json_null: Option<NullMode>,
+}
+
+/// Arguments of `collect`: the options, the callback, or both.
+pub struct CollectArgs(Option<CollectOptions>, Option<OwnedFunction>);
+
+impl<'lua> FromLuaMulti<'lua> for CollectArgs {
+    fn from_lua_multi(mut values: mlua::MultiValue<'lua>, lua: &'lua Lua) -> mlua::Result<Self> {
+        // `rows:collect(cb)` passes the callback in place of the options.
+        if let Some(mlua::Value::Function(_)) = values.iter().next() {
+            values.push_front(mlua::Value::Nil);
+        }
+
+        let (options, cb) = FromLuaMulti::from_lua_multi(values, lua)?;
+        Ok(CollectArgs(options, cb))
+    }
+}
+
+/// Rows fetched by `collect`, converted to Lua in one go once back on the Lua thread.
+pub struct CollectedRows {
+    columns: Vec<String>,
+    rows: Vec<Vec<libsql::Value>>,
+    conversions: Vec<Conversion>,
+    shape: RowShape,
+}
+
+impl IntoLua<'_> for CollectedRows {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value<'_>> {
+        let list = lua.create_table_with_capacity(self.rows.len(), 0)?;
+        for values in self.rows {
+            list.push(values_to_table(
+                lua,
+                &self.columns,
+                &self.conversions,
+                values,
+                self.shape,
+            )?)?;
+        }
+        Ok(mlua::Value::Table(list))
+    }
+}
+
+fn values_to_table<'lua>(
+    lua: &'lua Lua,
+    columns: &[String],
+    conversions: &[Conversion],
+    values: Vec<libsql::Value>,
+    shape: RowShape,
+) -> mlua::Result<mlua::Table<'lua>> {
+    let fields = values
+        .into_iter()
+        .zip(conversions)
+        .map(|(value, conversion)| FieldValue {
+            value,
+            conversion: *conversion,
+        });
+
+    match shape {
+        RowShape::Map => {
+            let table = lua.create_table_with_capacity(0, columns.len())?;
+            for (name, field) in columns.iter().zip(fields) {
+                table.raw_set(name.as_str(), field)?;
+            }
+            Ok(table)
+        }
+        RowShape::List => {
+            let table = lua.create_table_with_capacity(columns.len(), 0)?;
+            for (i, field) in fields.enumerate() {
+                table.raw_set(i + 1, field)?;
+            }
+            Ok(table)
+        }
+    }
+}
+
+fn closed() -> mlua::Error {
+    mlua::Error::RuntimeError("rows are closed".to_string())
+}
+
+fn column_names(rows: &libsql::Rows) -> Vec<String> {
+    (0..rows.column_count())
+        .map(|i| rows.column_name(i).unwrap_or_default().to_owned())
+        .collect()
+}
 
 #[derive(Clone)]
 pub struct LuaRows {
-    inner: Arc<RwLock<libsql::Rows>>,
+    /// `None` once the rows have been closed.
+    inner: Arc<RwLock<Option<libsql::Rows>>>,
     n_cols: Arc<OnceLock<i32>>,
+    /// Columns decoded when rows are read.
+    decode: Arc<Decode>,
+    types: TypeOptions,
+    /// What each column's declared type says, when `types` uses declared types.
+    declared: Arc<[Declared]>,
+    /// Column names and conversions, worked out for the first row and shared with every row.
+    layout: Arc<OnceLock<Layout>>,
 }
 
+type Layout = (Arc<[String]>, Arc<[Conversion]>);
+
 impl From<libsql::Rows> for LuaRows {
     fn from(rows: libsql::Rows) -> LuaRows {
         LuaRows {
-            inner: Arc::new(RwLock::new(rows)),
+            inner: Arc::new(RwLock::new(Some(rows))),
             n_cols: Arc::new(OnceLock::new()),
+            decode: Arc::default(),
+            types: TypeOptions::default(),
+            declared: Arc::new([]),
+            layout: Arc::new(OnceLock::new()),
         }
     }
 }
 
+#[lua_methods]
 impl LuaRows {
     pub fn new(rows: libsql::Rows) -> LuaRows {
-        LuaRows {
-            inner: Arc::new(RwLock::new(rows)),
-            n_cols: Arc::new(OnceLock::new()),
-        }
+        LuaRows::from(rows)
     }
 
-    #[luv_async]
-    pub async fn column_count(&self, cb: OwnedFunction) -> mlua::Result<i32> {
-        match self.n_cols.get().copied() {
-            Some(n_cols) => Ok(n_cols),
-            None => {
-                let n_cols = self.inner.read().await.column_count();
-                self.n_cols.set(n_cols).ok();
-                Ok(n_cols)
-            }
+    pub fn with_decode(mut self, decode: Arc<Decode>) -> LuaRows {
+        self.decode = decode;
+        self
+    }
+
+    /// Runs `sql` on `conn`, converting values as `types` says.
+    pub async fn query(
+        conn: &libsql::Connection,
+        sql: &str,
+        params: ParamsList,
+        types: TypeOptions,
+    ) -> mlua::Result<LuaRows> {
+        if !types.uses_declared_types() && !params.is_named() {
+            let rows = conn.query(sql, params.0).await.with_sql(sql)?;
+            return Ok(LuaRows::new(rows).with_types(types, Vec::new()));
         }
+
+        // Declared types and the names of placeholders are only known to prepared statements.
+        let mut stmt = conn.prepare(sql).await.with_sql(sql)?;
+        let declared = if types.uses_declared_types() {
+            declared_types(&stmt)
+        } else {
+            Vec::new()
+        };
+        let params = params.resolve(&stmt)?;
+        let rows = stmt.query(params).await.with_sql(sql)?;
+
+        Ok(LuaRows::new(rows).with_types(types, declared))
     }
 
-    fn column_count_sync(&self) -> mlua::Result<i32> {
-        Ok(self.column_count_internal())
+    pub fn with_types(mut self, types: TypeOptions, declared: Vec<Declared>) -> LuaRows {
+        self.types = types;
+        self.declared = declared.into();
+        self
     }
 
-    fn column_count_internal(&self) -> i32 {
-        if let Some(n_cols) = self.n_cols.get() {
-            return *n_cols;
-        }
-        let n_cols = self.inner.blocking_read().column_count();
-        self.n_cols.set(n_cols).ok();
-        n_cols
+    fn conversions(&self, columns: &[String], decode: &Decode) -> Vec<Conversion> {
+        decode
+            .decoders(columns)
+            .into_iter()
+            .enumerate()
+            .map(|(i, decoder)| Conversion {
+                types: self.types,
+                declared: self.declared.get(i).copied().unwrap_or_default(),
+                decoder,
+            })
+            .collect()
     }
 
-    #[luv_async]
-    pub fn column_name(&self, (index, cb): (mlua::Integer, OwnedFunction)) -> mlua::Result<String> {
-        match index {
-            i64::MIN..=-1 => Err(mlua::Error::RuntimeError(
-                "index must be greater than or equal to 0".to_string(),
-            )),
-            i if i >= self.column_count_internal() as i64 => Err(mlua::Error::RuntimeError(
-                "column index out of range".to_string(),
-            )),
-            i => self
-                .inner
-                .read()
-                .await
-                .column_name(i as i32)
-                .ok_or_else(|| mlua::Error::RuntimeError("column name not found".to_string()))
-                .map(ToOwned::to_owned),
-        }
+    #[lua_method(async, sync)]
+    pub async fn column_count(&self, cb: Option<OwnedFunction>) -> mlua::Result<i32> {
+        self.n_cols().await
     }
 
-    pub fn column_name_sync(&self, index: i64) -> mlua::Result<String> {
-        match index {
-            i64::MIN..=-1 => Err(mlua::Error::RuntimeError(
-                "index must be greater than or equal to 0".to_string(),
-            )),
-            i if i >= self.column_count_internal() as i64 => Err(mlua::Error::RuntimeError(
-                "column index out of range".to_string(),
-            )),
-            i => self
-                .inner
-                .blocking_read()
-                .column_name(i as i32)
-                .ok_or_else(|| mlua::Error::RuntimeError("column name not found".to_string()))
-                .map(ToOwned::to_owned),
-        }
+    #[lua_method(async, sync)]
+    pub async fn column_name(
+        &self,
+        (index, cb): (mlua::Integer, Option<OwnedFunction>),
+    ) -> mlua::Result<String> {
+        let index = self.column_index(index).await?;
+
+        let rows = self.inner.read().await;
+        rows.as_ref()
+            .ok_or_else(closed)?
+            .column_name(index)
+            .ok_or_else(|| mlua::Error::RuntimeError("column name not found".to_string()))
+            .map(ToOwned::to_owned)
     }
 
-    #[luv_async]
-    pub fn column_type(&self, (index, cb): (mlua::Integer, OwnedFunction)) -> mlua::Result<String> {
-        match index {
-            i64::MIN..=-1 => Err(mlua::Error::RuntimeError(
-                "index must be greater than or equal to 0".to_string(),
-            )),
-            i if i >= self.column_count_internal() as i64 => Err(mlua::Error::RuntimeError(
-                "column index out of range".to_string(),
-            )),
-            i => self
-                .inner
-                .read()
-                .await
-                .column_type(i as i32)
-                .into_lua_err()
-                .map(|t| match t {
-                    libsql::ValueType::Integer => "integer",
-                    libsql::ValueType::Real => "real",
-                    libsql::ValueType::Text => "text",
-                    libsql::ValueType::Blob => "blob",
-                    libsql::ValueType::Null => "null",
-                })
-                .map(ToOwned::to_owned),
-        }
+    #[lua_method(async, sync)]
+    pub async fn column_type(
+        &self,
+        (index, cb): (mlua::Integer, Option<OwnedFunction>),
+    ) -> mlua::Result<String> {
+        let index = self.column_index(index).await?;
+
+        let rows = self.inner.read().await;
+        rows.as_ref()
+            .ok_or_else(closed)?
+            .column_type(index)
+            .into_sql_err()
+            .map(|t| match t {
+                libsql::ValueType::Integer => "integer",
+                libsql::ValueType::Real => "real",
+                libsql::ValueType::Text => "text",
+                libsql::ValueType::Blob => "blob",
+                libsql::ValueType::Null => "null",
+            })
+            .map(ToOwned::to_owned)
     }
 
-    pub fn column_type_sync(&self, index: i64) -> mlua::Result<String> {
+    async fn n_cols(&self) -> mlua::Result<i32> {
+        let rows = self.inner.read().await;
+        let rows = rows.as_ref().ok_or_else(closed)?;
+
+        Ok(*self.n_cols.get_or_init(|| rows.column_count()))
+    }
+
+    /// Checks that `index` names one of the columns.
+    async fn column_index(&self, index: mlua::Integer) -> mlua::Result<i32> {
         match index {
             i64::MIN..=-1 => Err(mlua::Error::RuntimeError(
                 "index must be greater than or equal to 0".to_string(),
             )),
-            i if i >= self.column_count_internal() as i64 => Err(mlua::Error::RuntimeError(
+            i if i >= self.n_cols().await? as i64 => Err(mlua::Error::RuntimeError(
                 "column index out of range".to_string(),
             )),
-            i => self
-                .inner
-                .blocking_read()
-                .column_type(i as i32)
-                .map(|t| match t {
-                    libsql::ValueType::Integer => "integer",
-                    libsql::ValueType::Real => "real",
-                    libsql::ValueType::Text => "text",
-                    libsql::ValueType::Blob => "blob",
-                    libsql::ValueType::Null => "null",
-                })
-                .map(ToOwned::to_owned)
-                .into_lua_err(),
+            i => Ok(i as i32),
         }
     }
 
-    #[luv_async]
-    pub fn next(&self, cb: OwnedFunction) -> mlua::Result<Option<LuaRow>> {
+    #[lua_method(async, sync)]
+    pub async fn next(&self, cb: Option<OwnedFunction>) -> mlua::Result<Option<LuaRow>> {
         let mut writer = self.inner.write().await;
-        let rv = match writer.next().await.into_lua_err()? {
-            Some(row) => Some(LuaRow::new(row, writer.column_count())),
-            None => None,
+        let writer = writer.as_mut().ok_or_else(closed)?;
+        let Some(row) = writer.next().await.into_sql_err()? else {
+            return mlua::Result::Ok(None);
         };
-        mlua::Result::Ok(rv)
+
+        let (columns, conversions) = self.layout.get_or_init(|| {
+            let columns = column_names(writer);
+            let conversions = self.conversions(&columns, &self.decode);
+            (columns.into(), conversions.into())
+        });
+        mlua::Result::Ok(Some(LuaRow::new(
+            row,
+            Arc::clone(columns),
+            Arc::clone(conversions),
+        )))
     }
 
-    pub fn next_sync(&self) -> mlua::Result<Option<LuaRow>> {
-        let rt = tokio::runtime::Runtime::new().into_lua_err()?;
-        rt.block_on(async {
-            let mut writer = self.inner.blocking_write();
-            let rv = match writer.next().await.into_lua_err()? {
-                Some(row) => Some(LuaRow::new(row, writer.column_count())),
-                None => None,
+    /// Fetches the remaining rows (up to `limit`) in a single call.
+    #[luv_async(sync)]
+    pub async fn collect(
+        &self,
+        (options, cb): (Option<CollectOptions>, Option<OwnedFunction>),
+    ) -> mlua::Result<CollectedRows> {
+        let mut options = options.unwrap_or_default();
+        let mut decode = Decode::clone(&self.decode);
+        decode.columns.extend(options.decode.drain());
+        decode.json_null = options.json_null.unwrap_or(decode.json_null);
+
+        let mut rows = self.inner.write().await;
+        let rows = rows.as_mut().ok_or_else(closed)?;
+        let columns = column_names(rows);
+        let conversions = self.conversions(&columns, &decode);
+
+        LuaRows::collect_impl(rows, options, columns, conversions).await
+    }
+
+    /// `collect` as registered, which also takes the callback on its own.
+    fn collect_with<'lua>(
+        &self,
+        lua: &'lua Lua,
+        CollectArgs(options, cb): CollectArgs,
+    ) -> Pending<'lua> {
+        self.collect(lua, (options, cb))
+    }
+
+    /// Closes the rows, once a call running on them is done. Further calls on them fail.
+    #[lua_method(async, sync)]
+    pub async fn close(&self, cb: Option<OwnedFunction>) -> mlua::Result<()> {
+        self.inner.write().await.take();
+
+        mlua::Result::Ok(())
+    }
+
+    async fn collect_impl(
+        rows: &mut libsql::Rows,
+        options: CollectOptions,
+        columns: Vec<String>,
+        conversions: Vec<Conversion>,
+    ) -> mlua::Result<CollectedRows> {
+        let n_cols = columns.len() as i32;
+
+        let mut collected = Vec::new();
+        while options.limit.is_none_or(|limit| collected.len() < limit) {
+            let Some(row) = rows.next().await.into_sql_err()? else {
+                break;
             };
-            mlua::Result::Ok(rv)
+            let values = (0..n_cols)
+                .map(|i| row.get_value(i))
+                .collect::<Result<Vec<_>, _>>()
+                .into_sql_err()?;
+            collected.push(values);
+        }
+
+        Ok(CollectedRows {
+            columns,
+            rows: collected,
+            conversions,
+            shape: options.shape,
         })
     }
 }
 
 impl UserData for LuaRows {
     fn add_methods<'lua, M: mlua::prelude::LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("column_count", Self::column_count.wrap());
-        methods.add_method("column_count_sync", Self::column_count_sync.wrap());
-        methods.add_method("column_name", Self::column_name.wrap());
-        methods.add_method("column_name_sync", Self::column_name_sync.wrap());
-        methods.add_method("column_type", Self::column_type.wrap());
-        methods.add_method("column_type_sync", Self::column_type_sync.wrap());
+        Self::add_lua_methods(methods);
 
-        methods.add_method("next", Self::next.wrap());
-        methods.add_method("next_sync", Self::next.wrap());
+        methods.add_async_method("collect", Self::collect_with.wrap_async());
+        methods.add_method("collect_sync", Self::collect_sync.wrap());
 
         methods.add_meta_method("__call", Self::next_sync.wrap());
+        methods.add_meta_method("__close", Self::close_sync.wrap());
     }
 }
 
@@ -184,91 +368,206 @@ pub struct LuaRow(Arc<LuaRowInner>);
 struct LuaRowInner {
     row: libsql::Row,
     n_cols: i32,
+    /// Column names, cached so lookups by name don't cross into libsql for every access.
+    columns: Arc<[String]>,
+    conversions: Arc<[Conversion]>,
+}
+
+/// A column referenced by its 0-based position or by its name.
+pub enum ColumnIndex {
+    Position(mlua::Integer),
+    Name(String),
+}
+
+impl FromLua<'_> for ColumnIndex {
+    fn from_lua(value: mlua::Value, lua: &mlua::Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::String(name) => Ok(ColumnIndex::Name(name.to_str()?.to_owned())),
+            value => mlua::Integer::from_lua(value, lua).map(ColumnIndex::Position),
+        }
+    }
+}
+
+/// How the values of a column are converted to Lua.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Conversion {
+    types: TypeOptions,
+    declared: Declared,
+    /// Set when the column is decoded.
+    decoder: Option<Decoder>,
 }
 
-pub struct FieldValue(libsql::Value);
+pub struct FieldValue {
+    value: libsql::Value,
+    conversion: Conversion,
+}
 
 impl IntoLua<'_> for FieldValue {
-    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
-        match self.0 {
-            libsql::Value::Null => Ok(mlua::Value::Nil),
-            libsql::Value::Integer(i) => i.into_lua(lua),
-            libsql::Value::Real(f) => f.into_lua(lua),
-            libsql::Value::Text(str) => str.into_lua(lua),
-            libsql::Value::Blob(_bytes) => {
-                return Err(mlua::Error::RuntimeError(
-                    "blob not yet implemented".to_string(),
-                ))
-            }
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value<'_>> {
+        let Conversion {
+            types,
+            declared,
+            decoder,
+        } = self.conversion;
+
+        match (decoder, self.value) {
+            (Some(decoder), libsql::Value::Text(text)) => decoder.decode(lua, text.as_bytes()),
+            (Some(decoder), libsql::Value::Blob(bytes)) => decoder.decode(lua, &bytes),
+            (_, value) => types.to_lua(lua, value, declared),
         }
     }
 }
 
+#[lua_methods]
 impl LuaRow {
-    pub fn new(row: libsql::Row, n_cols: i32) -> LuaRow {
-        LuaRow(Arc::new(LuaRowInner { row, n_cols }))
+    pub fn new(row: libsql::Row, columns: Arc<[String]>, conversions: Arc<[Conversion]>) -> LuaRow {
+        LuaRow(Arc::new(LuaRowInner {
+            row,
+            n_cols: columns.len() as i32,
+            columns,
+            conversions,
+        }))
     }
 
-    pub fn get(&self, idx: mlua::Integer) -> mlua::Result<FieldValue> {
+    fn position(&self, idx: ColumnIndex) -> mlua::Result<i32> {
         match idx {
-            i if i < 0 => Err(mlua::Error::RuntimeError(
-                "index must be greater than 0".to_string(),
-            )),
-            i if i >= self.0.n_cols as i64 => Err(mlua::Error::RuntimeError(
-                "column index out of range".to_string(),
+            ColumnIndex::Position(i) if i < 0 => Err(mlua::Error::RuntimeError(
+                "index must be greater than or equal to 0".to_string(),
             )),
-            i => self
+            ColumnIndex::Position(i) if i >= self.0.n_cols as i64 => Err(
+                mlua::Error::RuntimeError("column index out of range".to_string()),
+            ),
+            ColumnIndex::Position(i) => Ok(i as i32),
+            ColumnIndex::Name(name) => self
                 .0
-                .row
-                .get_value(i as i32)
-                .into_lua_err()
-                .map(FieldValue),
+                .columns
+                .iter()
+                .position(|column| *column == name)
+                .map(|i| i as i32)
+                .ok_or_else(|| mlua::Error::RuntimeError(format!("no column named {name:?}"))),
         }
     }
 
+    #[lua_method]
+    pub fn get(&self, idx: ColumnIndex) -> mlua::Result<FieldValue> {
+        let i = self.position(idx)?;
+
+        Ok(FieldValue {
+            value: self.0.row.get_value(i).into_sql_err()?,
+            conversion: self.0.conversions[i as usize],
+        })
+    }
+
+    /// Like `get`, but returns blobs as `LuaBlob` userdata instead of Lua strings.
+    #[lua_method]
+    pub fn get_blob(&self, idx: ColumnIndex) -> mlua::Result<Option<LuaBlob>> {
+        let i = self.position(idx)?;
+
+        match self.0.row.get_value(i).into_sql_err()? {
+            libsql::Value::Blob(bytes) => Ok(Some(LuaBlob::new(bytes))),
+            libsql::Value::Null => Ok(None),
+            _ => Err(mlua::Error::RuntimeError(
+                "column is not a blob".to_string(),
+            )),
+        }
+    }
+
+    fn values(&self) -> mlua::Result<Vec<libsql::Value>> {
+        (0..self.0.n_cols)
+            .map(|i| self.0.row.get_value(i))
+            .collect::<Result<Vec<_>, _>>()
+            .into_sql_err()
+    }
+
+    /// Converts the row into a table keyed by column name.
+    #[lua_method]
+    pub fn to_table<'lua>(&self, lua: &'lua Lua, _: ()) -> mlua::Result<mlua::Table<'lua>> {
+        values_to_table(
+            lua,
+            &self.0.columns,
+            &self.0.conversions,
+            self.values()?,
+            RowShape::Map,
+        )
+    }
+
+    /// Converts the row into a list of values in column order.
+    #[lua_method]
+    pub fn to_list<'lua>(&self, lua: &'lua Lua, _: ()) -> mlua::Result<mlua::Table<'lua>> {
+        values_to_table(
+            lua,
+            &self.0.columns,
+            &self.0.conversions,
+            self.values()?,
+            RowShape::List,
+        )
+    }
+
+    #[lua_method]
     pub fn column_count(&self) -> mlua::Result<i32> {
         Ok(self.0.n_cols)
     }
 
+    #[lua_method]
     pub fn column_name(&self, idx: mlua::Integer) -> mlua::Result<String> {
-        match idx {
-            i64::MIN..=0 => Err(mlua::Error::RuntimeError(
-                "index must be greater than 0".to_string(),
-            )),
-            i if i >= self.0.n_cols as i64 => Err(mlua::Error::RuntimeError(
-                "column index out of range".to_string(),
-            )),
-            i => self
-                .0
-                .row
-                .column_name(i as i32)
-                .ok_or_else(|| mlua::Error::RuntimeError("column name not found".to_string()))
-                .map(ToOwned::to_owned),
-        }
+        let i = self.position(ColumnIndex::Position(idx))?;
+
+        Ok(self.0.columns[i as usize].clone())
     }
 
+    #[lua_method]
     pub fn column_type(&self, idx: mlua::Integer) -> mlua::Result<String> {
-        match idx {
-            i64::MIN..=0 => Err(mlua::Error::RuntimeError(
-                "index must be greater than 0".to_string(),
-            )),
-            i if i >= self.0.n_cols as i64 => Err(mlua::Error::RuntimeError(
-                "column index out of range".to_string(),
-            )),
-            i => self
-                .0
-                .row
-                .column_type(i as i32)
-                .into_lua_err()
-                .map(|v| match v {
-                    libsql::ValueType::Null => "null",
-                    libsql::ValueType::Integer => "integer",
-                    libsql::ValueType::Real => "real",
-                    libsql::ValueType::Text => "text",
-                    libsql::ValueType::Blob => "blob",
-                })
-                .map(ToOwned::to_owned),
+        let i = self.position(ColumnIndex::Position(idx))?;
+
+        self.0
+            .row
+            .column_type(i)
+            .into_sql_err()
+            .map(|v| match v {
+                libsql::ValueType::Null => "null",
+                libsql::ValueType::Integer => "integer",
+                libsql::ValueType::Real => "real",
+                libsql::ValueType::Text => "text",
+                libsql::ValueType::Blob => "blob",
+            })
+            .map(ToOwned::to_owned)
+    }
+
+    /// `row.name` lookup. Unknown names resolve to `nil`; methods take precedence over columns.
+    fn index(&self, key: mlua::Value) -> mlua::Result<Option<FieldValue>> {
+        let mlua::Value::String(name) = key else {
+            return Ok(None);
+        };
+        let name = name.to_str()?;
+        if !self.0.columns.iter().any(|column| column == name) {
+            return Ok(None);
         }
+
+        self.get(ColumnIndex::Name(name.to_owned())).map(Some)
+    }
+
+    /// Returns an iterator over `(column name, value)` pairs, for `row:pairs()` and `pairs(row)`.
+    #[lua_method]
+    pub fn pairs<'lua>(&self, lua: &'lua Lua, _: ()) -> mlua::Result<mlua::Function<'lua>> {
+        let row = self.clone();
+        let next = std::cell::Cell::new(0);
+
+        lua.create_function(move |_lua, _: mlua::MultiValue| {
+            let i = next.get();
+            if i >= row.0.n_cols {
+                return Ok((None, None));
+            }
+            next.set(i + 1);
+
+            let value = row.0.row.get_value(i).into_sql_err()?;
+            Ok((
+                Some(row.0.columns[i as usize].clone()),
+                Some(FieldValue {
+                    value,
+                    conversion: row.0.conversions[i as usize],
+                }),
+            ))
+        })
     }
 }
 
@@ -276,9 +575,124 @@ impl UserData for LuaRow {
     fn add_fields<'lua, F: mlua::prelude::LuaUserDataFields<'lua, Self>>(_fields: &mut F) {}
 
     fn add_methods<'lua, M: mlua::prelude::LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("get", Self::get.wrap());
-        methods.add_method("column_count", Self::column_count.wrap());
-        methods.add_method("column_name", Self::column_name.wrap());
-        methods.add_method("column_type", Self::column_type.wrap());
+        Self::add_lua_methods(methods);
+
+        methods.add_meta_method(MetaMethod::Index, Self::index.wrap());
+        methods.add_meta_method(MetaMethod::Len, Self::column_count.wrap());
+        // `MetaMethod::Pairs` is gated on Lua 5.2+; LuaJIT honours `__pairs` only when built with
+        // 5.2 compatibility, which Neovim's isn't. `row:pairs()` works everywhere.
+        methods.add_meta_method("__pairs", Self::pairs.wrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luajit2_sys as _;
+
+    use super::*;
+
+    fn rows(sql: &str) -> LuaRows {
+        crate::runtime::block_on(async {
+            let db = libsql::Builder::new_local(":memory:")
+                .build()
+                .await
+                .into_sql_err()?;
+            let conn = db.connect().into_sql_err()?;
+            let params = ParamsList::from(libsql::params::Params::None);
+            LuaRows::query(&conn, sql, params, TypeOptions::default()).await
+        })
+        .unwrap()
+    }
+
+    fn collect_args(lua: &Lua, args: &str) -> CollectArgs {
+        let values = lua.load(args).eval::<mlua::MultiValue>().unwrap();
+        CollectArgs::from_lua_multi(values, lua).unwrap()
+    }
+
+    #[test]
+    fn collect_takes_options_and_callback_in_any_combination() {
+        let lua = Lua::new();
+
+        let CollectArgs(options, cb) = collect_args(&lua, "return function() end");
+        assert!(options.is_none() && cb.is_some());
+
+        let CollectArgs(options, cb) = collect_args(&lua, "return nil, function() end");
+        assert!(options.is_none() && cb.is_some());
+
+        let CollectArgs(options, cb) = collect_args(&lua, "return { limit = 2 }, function() end");
+        assert_eq!(options.unwrap().limit, Some(2));
+        assert!(cb.is_some());
+
+        let CollectArgs(options, cb) = collect_args(&lua, "return { limit = 2 }");
+        assert_eq!(options.unwrap().limit, Some(2));
+        assert!(cb.is_none());
+
+        let CollectArgs(options, cb) = collect_args(&lua, "return");
+        assert!(options.is_none() && cb.is_none());
+    }
+
+    #[test]
+    fn pairs_iterates_columns_in_order() {
+        let lua = Lua::new();
+        let row = rows("SELECT 1 AS a, 'x' AS b")
+            .next_sync()
+            .unwrap()
+            .unwrap();
+        lua.globals().set("row", row).unwrap();
+
+        let seen: String = lua
+            .load(
+                r#"
+                local seen = {}
+                for name, value in row:pairs() do
+                    seen[#seen + 1] = name .. "=" .. tostring(value)
+                end
+                return table.concat(seen, ",")
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(seen, "a=1,b=x");
+    }
+
+    #[test]
+    fn rows_convert_to_maps_and_lists() {
+        let lua = Lua::new();
+        lua.load("vim = { NIL = newproxy(false) }").exec().unwrap();
+        let rows = rows(
+            "SELECT 1 AS a, NULL AS b, 'x' AS c
+             UNION ALL SELECT 2, NULL, 'y'
+             UNION ALL SELECT 3, NULL, 'z'",
+        );
+        lua.globals().set("rows", rows).unwrap();
+
+        let seen: String = lua
+            .load(
+                r#"
+                local function map(t)
+                    local fields = {}
+                    for name, value in pairs(t) do
+                        fields[#fields + 1] = name .. "=" .. tostring(value)
+                    end
+                    table.sort(fields)
+                    return table.concat(fields, ",")
+                end
+                local function list(t)
+                    return tostring(t[1]) .. "," .. tostring(t[2]) .. "," .. tostring(t[3])
+                end
+
+                local first = rows:next_sync()
+                local seen = { map(first:to_table()), list(first:to_list()) }
+                local limited = rows:collect_sync({ limit = 1, as = "list" })
+                seen[#seen + 1] = #limited .. ":" .. list(limited[1])
+                local rest = rows:collect_sync()
+                seen[#seen + 1] = #rest .. ":" .. map(rest[1])
+                return table.concat(seen, " ")
+                "#,
+            )
+            .eval()
+            .unwrap();
+        // NULLs come back as nil: left out of maps, and holes in lists.
+        assert_eq!(seen, "a=1,c=x 1,nil,x 1:2,nil,y 1:a=3,c=z");
     }
 }
@@ -0,0 +1,77 @@
+//! The process-wide Tokio runtime that all async and sync entry points dispatch onto.
+//!
+//! The runtime is created lazily on first use. Call `configure` (exposed to Lua as
+//! `libsql.configure_runtime`) before the first query to change its settings.
+
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+
+use libsql_nvim_derive::FromLuaSerde;
+use mlua::serde::LuaSerdeExt;
+
+use crate::prelude::*;
+
+static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+
+static CONFIG: Mutex<RuntimeConfig> = Mutex::new(RuntimeConfig {
+    worker_threads: None,
+});
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub struct RuntimeConfig {
+    /// Number of worker threads. Defaults to the number of CPU cores.
+    worker_threads: Option<usize>,
+}
+
+/// Sets the configuration used to build the runtime. Fails if the runtime is already running.
+pub fn configure(config: RuntimeConfig) -> mlua::Result<()> {
+    if RUNTIME.get().is_some() {
+        return Err(mlua::Error::RuntimeError(
+            "runtime is already running and can no longer be configured".to_string(),
+        ));
+    }
+    if config.worker_threads == Some(0) {
+        return Err(mlua::Error::RuntimeError(
+            "worker_threads must be greater than 0".to_string(),
+        ));
+    }
+
+    *CONFIG.lock().map_err(|_| {
+        mlua::Error::RuntimeError("runtime config lock poisoned".to_string())
+    })? = config;
+
+    Ok(())
+}
+
+/// Returns the shared runtime, starting it if this is the first call.
+pub fn get() -> mlua::Result<&'static tokio::runtime::Runtime> {
+    if let Some(rt) = RUNTIME.get() {
+        return Ok(rt);
+    }
+
+    let config = CONFIG
+        .lock()
+        .map_err(|_| mlua::Error::RuntimeError("runtime config lock poisoned".to_string()))?
+        .clone();
+
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all().thread_name("libsql-nvim");
+    if let Some(worker_threads) = config.worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    let rt = builder.build().into_lua_err()?;
+
+    // Only the Lua thread starts the runtime, so losing this race is not expected. If it does
+    // happen the extra runtime is simply dropped.
+    Ok(RUNTIME.get_or_init(|| rt))
+}
+
+/// Blocks the calling thread until `fut` completes on the shared runtime.
+///
+/// Must not be called from inside the runtime itself.
+pub fn block_on<F, T>(fut: F) -> mlua::Result<T>
+where
+    F: Future<Output = mlua::Result<T>>,
+{
+    get()?.block_on(fut)
+}
@@ -0,0 +1,423 @@
+//! `Schema`, what `db:schema` and `conn:schema` return: the tables, views, indexes and triggers of
+//! the main database, read from `sqlite_schema` and the table-valued pragma functions.
+
+use std::collections::HashMap;
+
+use mlua::serde::LuaSerdeExt;
+use mlua::IntoLua;
+
+use crate::error::SqlResultExt;
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Schema {
+    tables: Vec<Table>,
+    views: Vec<View>,
+    indexes: Vec<Index>,
+    triggers: Vec<Trigger>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Table {
+    name: String,
+    /// The `CREATE TABLE` statement.
+    sql: Option<String>,
+    columns: Vec<Column>,
+    foreign_keys: Vec<ForeignKey>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct View {
+    name: String,
+    sql: Option<String>,
+    columns: Vec<Column>,
+    /// Why the columns could not be read, e.g. for a view of a table that has been dropped.
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Index {
+    name: String,
+    table: String,
+    sql: Option<String>,
+    unique: bool,
+    /// Indexed columns in order. Expressions have no name and are left out.
+    columns: Vec<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Trigger {
+    name: String,
+    table: String,
+    sql: Option<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Column {
+    name: String,
+    /// Declared type, as written in the `CREATE` statement.
+    #[serde(rename = "type")]
+    decl_type: Option<String>,
+    notnull: bool,
+    /// Default value, as SQL text.
+    default: Option<String>,
+    /// Position in the primary key, starting at 1, or 0 if the column isn't part of it.
+    pk: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ForeignKey {
+    /// The referenced table.
+    table: String,
+    from: Vec<String>,
+    /// Referenced columns, `nil` where the key refers to the primary key of `table`.
+    to: Vec<Option<String>>,
+    on_update: String,
+    on_delete: String,
+}
+
+impl Schema {
+    /// Reads the schema through `conn`, leaving out SQLite's internal objects.
+    pub async fn read(conn: &libsql::Connection) -> mlua::Result<Schema> {
+        let mut schema = Schema::default();
+        let mut columns = table_columns(conn).await?;
+        let mut foreign_keys = foreign_keys(conn).await?;
+        let mut indexes = indexes(conn).await?;
+
+        let objects = query(
+            conn,
+            r"SELECT type, name, tbl_name, sql FROM sqlite_schema
+              WHERE name NOT LIKE 'sqlite\_%' ESCAPE '\' ORDER BY name",
+            (),
+        )
+        .await?;
+        for object in objects {
+            let kind = text(&object, 0)?;
+            let name = text(&object, 1)?;
+            let table = text(&object, 2)?;
+            let sql = optional_text(&object, 3)?;
+
+            match kind.as_str() {
+                "table" => schema.tables.push(Table {
+                    columns: columns.remove(&name).unwrap_or_default(),
+                    foreign_keys: foreign_keys.remove(&name).unwrap_or_default(),
+                    name,
+                    sql,
+                }),
+                "view" => {
+                    // Reading the columns runs the view's query, which fails if it no longer
+                    // matches the tables it reads from. Only that view is left without columns.
+                    let (columns, error) = match view_columns(conn, &name).await {
+                        Ok(columns) => (columns, None),
+                        Err(err) => (Vec::new(), Some(err.to_string())),
+                    };
+                    schema.views.push(View {
+                        columns,
+                        error,
+                        name,
+                        sql,
+                    })
+                }
+                "index" => {
+                    let (unique, columns) = indexes.remove(&name).unwrap_or_default();
+                    schema.indexes.push(Index {
+                        unique,
+                        columns,
+                        name,
+                        table,
+                        sql,
+                    })
+                }
+                "trigger" => schema.triggers.push(Trigger { name, table, sql }),
+                _ => {}
+            }
+        }
+
+        Ok(schema)
+    }
+}
+
+impl IntoLua<'_> for Schema {
+    fn into_lua(self, lua: &mlua::Lua) -> mlua::Result<mlua::Value<'_>> {
+        lua.to_value_with(
+            &self,
+            mlua::SerializeOptions::new().serialize_none_to_null(false),
+        )
+    }
+}
+
+type Row = Vec<libsql::Value>;
+
+/// Values read for each object, keyed by the object's name.
+type ByObject<T> = HashMap<String, T>;
+
+/// Runs `sql`, reading the values out of each row before stepping to the next, since local rows
+/// are views into their statement.
+async fn query(
+    conn: &libsql::Connection,
+    sql: &str,
+    params: impl libsql::params::IntoParams,
+) -> mlua::Result<Vec<Row>> {
+    let mut rows = conn.query(sql, params).await.with_sql(sql)?;
+    let n_cols = rows.column_count();
+
+    let mut collected = Vec::new();
+    while let Some(row) = rows.next().await.with_sql(sql)? {
+        let values = (0..n_cols)
+            .map(|i| row.get_value(i))
+            .collect::<Result<Row, _>>()
+            .with_sql(sql)?;
+        collected.push(values);
+    }
+    Ok(collected)
+}
+
+fn optional_text(row: &Row, i: usize) -> mlua::Result<Option<String>> {
+    match &row[i] {
+        libsql::Value::Null => Ok(None),
+        libsql::Value::Text(text) => Ok(Some(text.clone())),
+        libsql::Value::Integer(n) => Ok(Some(n.to_string())),
+        libsql::Value::Real(n) => Ok(Some(n.to_string())),
+        libsql::Value::Blob(_) => Err(mlua::Error::RuntimeError(format!(
+            "unexpected blob in schema column {i}"
+        ))),
+    }
+}
+
+fn text(row: &Row, i: usize) -> mlua::Result<String> {
+    optional_text(row, i).map(Option::unwrap_or_default)
+}
+
+fn integer(row: &Row, i: usize) -> mlua::Result<i64> {
+    match row[i] {
+        libsql::Value::Integer(n) => Ok(n),
+        _ => Err(mlua::Error::RuntimeError(format!(
+            "expected an integer in schema column {i}"
+        ))),
+    }
+}
+
+/// The columns of every table.
+async fn table_columns(conn: &libsql::Connection) -> mlua::Result<ByObject<Vec<Column>>> {
+    let rows = query(
+        conn,
+        r#"SELECT c.name, c.type, c."notnull", c.dflt_value, c.pk, s.name
+           FROM sqlite_schema AS s, pragma_table_info(s.name) AS c
+           WHERE s.type = 'table' AND s.name NOT LIKE 'sqlite\_%' ESCAPE '\'
+           ORDER BY s.name, c.cid"#,
+        (),
+    )
+    .await?;
+
+    let mut columns: ByObject<Vec<Column>> = HashMap::new();
+    for row in &rows {
+        columns.entry(text(row, 5)?).or_default().push(column(row)?);
+    }
+    Ok(columns)
+}
+
+/// The columns of the view `name`. Read one view at a time, as a view that fails to compile would
+/// fail a query over all of them.
+async fn view_columns(conn: &libsql::Connection, name: &str) -> mlua::Result<Vec<Column>> {
+    let rows = query(
+        conn,
+        r#"SELECT name, type, "notnull", dflt_value, pk FROM pragma_table_info(?) ORDER BY cid"#,
+        [name],
+    )
+    .await?;
+
+    rows.iter().map(column).collect()
+}
+
+/// A column from a row of `pragma_table_info`, starting with its name.
+fn column(row: &Row) -> mlua::Result<Column> {
+    Ok(Column {
+        name: text(row, 0)?,
+        decl_type: optional_text(row, 1)?.filter(|decl_type| !decl_type.is_empty()),
+        notnull: integer(row, 2)? != 0,
+        default: optional_text(row, 3)?,
+        pk: integer(row, 4)?,
+    })
+}
+
+/// The foreign keys of every table.
+async fn foreign_keys(conn: &libsql::Connection) -> mlua::Result<ByObject<Vec<ForeignKey>>> {
+    let rows = query(
+        conn,
+        r#"SELECT s.name, f.id, f."table", f."from", f."to", f.on_update, f.on_delete
+           FROM sqlite_schema AS s, pragma_foreign_key_list(s.name) AS f
+           WHERE s.type = 'table' AND s.name NOT LIKE 'sqlite\_%' ESCAPE '\'
+           ORDER BY s.name, f.id, f.seq"#,
+        (),
+    )
+    .await?;
+
+    // One row per column of each key, keys spanning consecutive rows with the same id.
+    let mut keys: ByObject<Vec<(i64, ForeignKey)>> = HashMap::new();
+    for row in &rows {
+        let table = keys.entry(text(row, 0)?).or_default();
+        let id = integer(row, 1)?;
+        if table.last().map(|(last, _)| *last) != Some(id) {
+            table.push((
+                id,
+                ForeignKey {
+                    table: text(row, 2)?,
+                    from: Vec::new(),
+                    to: Vec::new(),
+                    on_update: text(row, 5)?,
+                    on_delete: text(row, 6)?,
+                },
+            ));
+        }
+        let (_, key) = table.last_mut().expect("a key was just pushed");
+        key.from.push(text(row, 3)?);
+        key.to.push(optional_text(row, 4)?);
+    }
+
+    Ok(keys
+        .into_iter()
+        .map(|(table, keys)| (table, keys.into_iter().map(|(_, key)| key).collect()))
+        .collect())
+}
+
+/// Whether each index is unique, and its named columns in order. Expressions are left out.
+async fn indexes(conn: &libsql::Connection) -> mlua::Result<ByObject<(bool, Vec<String>)>> {
+    let rows = query(
+        conn,
+        r#"SELECT s.name, l."unique", i.name
+           FROM sqlite_schema AS s
+           JOIN pragma_index_list(s.tbl_name) AS l ON l.name = s.name
+           JOIN pragma_index_info(s.name) AS i
+           WHERE s.type = 'index' AND s.name NOT LIKE 'sqlite\_%' ESCAPE '\'
+           ORDER BY s.name, i.seqno"#,
+        (),
+    )
+    .await?;
+
+    let mut indexes: ByObject<(bool, Vec<String>)> = HashMap::new();
+    for row in &rows {
+        let (unique, columns) = indexes.entry(text(row, 0)?).or_default();
+        *unique = integer(row, 1)? != 0;
+        columns.extend(optional_text(row, 2)?);
+    }
+    Ok(indexes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(sql: &str) -> Schema {
+        crate::runtime::block_on(async {
+            let db = libsql::Builder::new_local(":memory:")
+                .build()
+                .await
+                .into_sql_err()?;
+            let conn = db.connect().into_sql_err()?;
+            conn.execute_batch(sql).await.into_sql_err()?;
+            Schema::read(&conn).await
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_tables_with_composite_foreign_keys_and_unique_indexes() {
+        let schema = read(
+            "CREATE TABLE parent (a INTEGER NOT NULL, b TEXT DEFAULT 'x', PRIMARY KEY (a, b));
+             CREATE TABLE child (
+                 id INTEGER PRIMARY KEY,
+                 pa INTEGER,
+                 pb TEXT,
+                 FOREIGN KEY (pa, pb) REFERENCES parent (a, b) ON DELETE CASCADE
+             );
+             CREATE UNIQUE INDEX child_pair ON child (pb, pa);
+             CREATE INDEX child_expr ON child (pa + 1, id);
+             CREATE VIEW kids AS SELECT id FROM child;
+             CREATE TRIGGER child_insert AFTER INSERT ON child BEGIN SELECT 1; END;
+             CREATE TABLE sqlitex (y);",
+        );
+
+        let tables: Vec<_> = schema.tables.iter().map(|t| t.name.as_str()).collect();
+        // `sqlitex` isn't internal, but would match an unescaped `sqlite_%`.
+        assert_eq!(tables, ["child", "parent", "sqlitex"]);
+
+        let parent = &schema.tables[1];
+        let columns: Vec<_> = parent
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.decl_type.as_deref(), c.notnull, c.pk))
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                ("a", Some("INTEGER"), true, 1),
+                ("b", Some("TEXT"), false, 2)
+            ]
+        );
+        assert_eq!(parent.columns[1].default.as_deref(), Some("'x'"));
+        assert!(parent.foreign_keys.is_empty());
+
+        let child = &schema.tables[0];
+        assert_eq!(child.foreign_keys.len(), 1);
+        let key = &child.foreign_keys[0];
+        assert_eq!(key.table, "parent");
+        assert_eq!(key.from, ["pa", "pb"]);
+        assert_eq!(key.to, [Some("a".to_string()), Some("b".to_string())]);
+        assert_eq!(key.on_delete, "CASCADE");
+        assert_eq!(key.on_update, "NO ACTION");
+
+        // The primary key's automatic index is internal.
+        let indexes: Vec<_> = schema
+            .indexes
+            .iter()
+            .map(|i| {
+                (
+                    i.name.as_str(),
+                    i.table.as_str(),
+                    i.unique,
+                    i.columns.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            indexes,
+            [
+                ("child_expr", "child", false, vec!["id".to_string()]),
+                (
+                    "child_pair",
+                    "child",
+                    true,
+                    vec!["pb".to_string(), "pa".to_string()]
+                ),
+            ]
+        );
+
+        assert_eq!(schema.views.len(), 1);
+        assert_eq!(schema.views[0].name, "kids");
+        assert_eq!(schema.views[0].columns[0].name, "id");
+        assert_eq!(schema.views[0].error, None);
+        assert_eq!(schema.triggers.len(), 1);
+        assert_eq!(schema.triggers[0].table, "child");
+    }
+
+    #[test]
+    fn broken_views_keep_the_rest_of_the_schema() {
+        let schema = read(
+            "CREATE TABLE t (x);
+             CREATE TABLE gone (y);
+             CREATE VIEW broken AS SELECT y FROM gone;
+             CREATE VIEW working AS SELECT x FROM t;
+             DROP TABLE gone;",
+        );
+
+        assert_eq!(schema.tables.len(), 1);
+        let views: Vec<_> = schema
+            .views
+            .iter()
+            .map(|v| (v.name.as_str(), v.columns.len()))
+            .collect();
+        assert_eq!(views, [("broken", 0), ("working", 1)]);
+        let error = schema.views[0].error.as_deref().unwrap();
+        assert!(error.contains("no such table"), "{error}");
+        assert_eq!(schema.views[1].error, None);
+    }
+}
@@ -1,12 +1,20 @@
-pub(crate) struct LuaSerializer<'lua>(mlua::Value<'lua>);
+use crate::prelude::*;
+use crate::{blob::LuaBlob, json, types};
+
+pub(crate) struct LuaSerializer<'lua>(&'lua Lua, mlua::Value<'lua>);
 
 impl<'lua> LuaSerializer<'lua> {
-    pub fn new(value: mlua::Value<'lua>) -> Self {
-        LuaSerializer(value)
+    pub fn new(lua: &'lua Lua, value: mlua::Value<'lua>) -> Self {
+        LuaSerializer(lua, value)
     }
 
     pub fn into_sql(self) -> mlua::Result<libsql::Value> {
-        match self.0 {
+        // `vim.NIL`, as returned for nulls.
+        if types::is_null(self.0, &self.1)? {
+            return Ok(libsql::Value::Null);
+        }
+
+        match self.1 {
             mlua::Value::Nil => Ok(libsql::Value::Null),
             mlua::Value::Boolean(b) => {
                 if b {
@@ -19,24 +27,24 @@ impl<'lua> LuaSerializer<'lua> {
             mlua::Value::Number(f) => Ok(libsql::Value::Real(f)),
             mlua::Value::String(s) => {
                 let s = s.to_str().map_err(|_| {
-                    mlua::Error::RuntimeError("string is not valid utf-8".to_string())
+                    mlua::Error::RuntimeError(
+                        "string is not valid utf-8, use libsql.blob() to bind binary data"
+                            .to_string(),
+                    )
                 })?;
                 Ok(libsql::Value::Text(s.to_string()))
             }
-            mlua::Value::Table(_tbl) => {
-                return Err(mlua::Error::RuntimeError(
-                    "table is not supported as a parameter".to_string(),
-                ))
-            }
+            mlua::Value::Table(_) => Ok(libsql::Value::Text(json::to_text(self.0, &self.1)?)),
+            mlua::Value::UserData(ud) if ud.is::<LuaBlob>() => Ok(libsql::Value::Blob(
+                ud.borrow::<LuaBlob>()?.as_bytes().to_vec(),
+            )),
             mlua::Value::LightUserData(_)
             | mlua::Value::Function(_)
             | mlua::Value::Thread(_)
             | mlua::Value::UserData(_)
-            | mlua::Value::Error(_) => {
-                return Err(mlua::Error::RuntimeError(
-                    "unsupported value type".to_string(),
-                ))
-            }
+            | mlua::Value::Error(_) => Err(mlua::Error::RuntimeError(
+                "unsupported value type".to_string(),
+            )),
         }
     }
 }
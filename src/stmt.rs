@@ -0,0 +1,205 @@
+use std::sync::Arc;
+
+use libsql_nvim_derive::lua_methods;
+use mlua::{OwnedFunction, UserData};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::conn::SharedConnection;
+use crate::error::SqlResultExt;
+use crate::json::Decode;
+use crate::task::Timeout;
+use crate::types::{declared_types, TypeOptions};
+use crate::{conn::ParamsList, prelude::*, rows::LuaRows};
+
+fn busy() -> mlua::Error {
+    mlua::Error::RuntimeError("statement is busy with another call".to_string())
+}
+
+#[derive(Clone)]
+pub struct LuaStatement {
+    stmt: Arc<RwLock<libsql::Statement>>,
+    sql: Arc<str>,
+    /// The connection the statement was prepared on. Calls take its lock like calls on the
+    /// connection do.
+    conn: SharedConnection,
+    /// The timeout of the connection the statement was prepared on.
+    timeout: Timeout,
+    decode: Arc<Decode>,
+    types: TypeOptions,
+}
+
+#[lua_methods]
+impl LuaStatement {
+    pub fn new(
+        stmt: libsql::Statement,
+        sql: String,
+        conn: SharedConnection,
+        timeout: Timeout,
+        decode: Arc<Decode>,
+        types: TypeOptions,
+    ) -> Self {
+        LuaStatement {
+            stmt: Arc::new(RwLock::new(stmt)),
+            sql: sql.into(),
+            conn,
+            timeout,
+            decode,
+            types,
+        }
+    }
+
+    #[lua_method(async, sync, timeout = self.timeout.get())]
+    pub async fn execute(
+        &self,
+        (params, cb): (ParamsList, Option<OwnedFunction>),
+    ) -> mlua::Result<u64> {
+        let _conn = self.conn.lock().await;
+        let mut stmt = self.stmt.write().await;
+        // libsql binds without resetting, and a statement that has been stepped ignores bindings.
+        stmt.reset();
+
+        let params = params.resolve(&stmt)?;
+        let affected = stmt.execute(params).await.with_sql(&self.sql)?;
+
+        mlua::Result::Ok(affected as u64)
+    }
+
+    #[lua_method(async, sync, timeout = self.timeout.get())]
+    pub async fn query(
+        &self,
+        (params, cb): (ParamsList, Option<OwnedFunction>),
+    ) -> mlua::Result<LuaRows> {
+        let _conn = self.conn.lock().await;
+        let mut stmt = self.stmt.write().await;
+        // Also ends the rows of the previous query on the statement.
+        stmt.reset();
+
+        let declared = if self.types.uses_declared_types() {
+            declared_types(&stmt)
+        } else {
+            Vec::new()
+        };
+        let params = params.resolve(&stmt)?;
+        let rows = stmt.query(params).await.with_sql(&self.sql)?;
+
+        mlua::Result::Ok(
+            LuaRows::new(rows)
+                .with_types(self.types, declared)
+                .with_decode(Arc::clone(&self.decode)),
+        )
+    }
+
+    /// Resets the statement so it can be executed again with new bindings.
+    #[lua_method]
+    pub fn reset(&self) -> mlua::Result<()> {
+        self.idle_mut()?.reset();
+        Ok(())
+    }
+
+    #[lua_method]
+    pub fn parameter_count(&self) -> mlua::Result<usize> {
+        Ok(self.idle()?.parameter_count())
+    }
+
+    /// Returns a list of `{ name, origin_name, table_name, database_name, decl_type }` tables,
+    /// one per result column.
+    #[lua_method]
+    pub fn columns<'lua>(&self, lua: &'lua Lua, _: ()) -> mlua::Result<mlua::Table<'lua>> {
+        let stmt = self.idle()?;
+        let columns = stmt.columns();
+
+        let list = lua.create_table_with_capacity(columns.len(), 0)?;
+        for column in columns {
+            let info = lua.create_table_with_capacity(0, 5)?;
+            info.set("name", column.name())?;
+            info.set("origin_name", column.origin_name())?;
+            info.set("table_name", column.table_name())?;
+            info.set("database_name", column.database_name())?;
+            info.set("decl_type", column.decl_type())?;
+            list.push(info)?;
+        }
+
+        Ok(list)
+    }
+
+    /// The statement, for use from the Lua thread. Fails instead of blocking the editor while a
+    /// call is running on it.
+    fn idle(&self) -> mlua::Result<RwLockReadGuard<'_, libsql::Statement>> {
+        self.stmt.try_read().map_err(|_| busy())
+    }
+
+    fn idle_mut(&self) -> mlua::Result<RwLockWriteGuard<'_, libsql::Statement>> {
+        self.stmt.try_write().map_err(|_| busy())
+    }
+}
+
+impl UserData for LuaStatement {
+    fn add_fields<'lua, F: mlua::prelude::LuaUserDataFields<'lua, Self>>(_fields: &mut F) {}
+
+    fn add_methods<'lua, M: mlua::prelude::LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        Self::add_lua_methods(methods);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luajit2_sys as _;
+
+    use crate::db::LuaDatabase;
+    use crate::prelude::*;
+
+    #[test]
+    fn statements_rebind_on_every_call() {
+        let lua = Lua::new();
+        let config = serde_json::json!({ "kind": "memory" });
+        let db = LuaDatabase::create_sync(serde_json::from_value(config).unwrap()).unwrap();
+        lua.globals()
+            .set("conn", db.connect_sync().unwrap())
+            .unwrap();
+
+        let sums: (i64, i64) = lua
+            .load(
+                r#"
+                conn:execute_batch_sync("CREATE TABLE t (x)")
+                local insert = conn:prepare_sync("INSERT INTO t VALUES (?)")
+                insert:execute_sync({ 1 })
+                insert:execute_sync({ 2 })
+
+                local sum = conn:prepare_sync("SELECT sum(x) FROM t WHERE x >= ?")
+                local all = sum:query_sync({ 1 }):next_sync():get(0)
+                local some = sum:query_sync({ 2 }):next_sync():get(0)
+                return all, some
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(sums, (3, 2));
+    }
+
+    #[test]
+    fn statements_and_transactions_decode_like_their_connection() {
+        let lua = Lua::new();
+        let config = serde_json::json!({ "kind": "memory" });
+        let db = LuaDatabase::create_sync(serde_json::from_value(config).unwrap()).unwrap();
+        lua.globals()
+            .set("conn", db.connect_sync().unwrap())
+            .unwrap();
+
+        let decoded: (i64, i64) = lua
+            .load(
+                r#"
+                local decoding = conn:with_decode({ j = "json" })
+                local stmt = decoding:prepare_sync([[SELECT '{"a": 1}' AS j]])
+                local from_stmt = stmt:query_sync():next_sync().j.a
+
+                local tx = decoding:transaction_sync()
+                local from_tx = tx:query_sync([[SELECT '[2]' AS j]]):next_sync().j[1]
+                tx:commit_sync()
+                return from_stmt, from_tx
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(decoded, (1, 2));
+    }
+}
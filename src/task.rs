@@ -0,0 +1,192 @@
+//! Delivers the results of `#[luv_async]` methods back to Lua.
+//!
+//! The method body runs on the shared runtime and its result is handed to the Lua thread through a
+//! libuv async handle, which settles the `LuaFuture` returned for the call. A trailing
+//! `cb(err, result)` callback is called from there, and a coroutine that called the method without
+//! one is resumed with the result.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use mlua::OwnedFunction;
+
+use crate::error::LuaSqlError;
+use crate::future::{LuaFuture, Pending};
+use crate::prelude::*;
+
+type Slot<T> = Arc<Mutex<Option<mlua::Result<T>>>>;
+
+/// Timeout for the calls made through a connection, shared with the statements and transactions
+/// created from it.
+#[derive(Debug, Clone, Default)]
+pub struct Timeout(Arc<Mutex<Option<Duration>>>);
+
+impl Timeout {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Timeout(Arc::new(Mutex::new(timeout)))
+    }
+
+    pub fn get(&self) -> Option<Duration> {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn set(&self, timeout: Option<Duration>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = timeout;
+    }
+}
+
+/// Runs `fut` on the shared runtime, failing it with a `timeout` error if it runs past `timeout`.
+///
+/// With a callback, or outside of a coroutine, the call returns a `LuaFuture` for the result.
+/// Otherwise the calling coroutine yields until the result is ready.
+pub fn spawn<'lua, T, F>(
+    lua: &'lua Lua,
+    cb: Option<OwnedFunction>,
+    timeout: Option<Duration>,
+    fut: F,
+) -> Pending<'lua>
+where
+    T: for<'a> IntoLuaMulti<'a> + Send + 'static,
+    F: Future<Output = mlua::Result<T>> + Send + 'static,
+{
+    match start(lua, timeout, fut) {
+        Ok(future) => deliver(lua, future, cb),
+        Err(err) => Pending::ready(Err(err)),
+    }
+}
+
+/// Runs `fut` on the shared runtime and blocks until it is done, for the `_sync` variants of async
+/// methods. Fails with a `timeout` error if it runs past `timeout`, like `spawn`.
+pub fn block_on<T, F>(timeout: Option<Duration>, fut: F) -> mlua::Result<T>
+where
+    T: Send + 'static,
+    F: Future<Output = mlua::Result<T>> + Send + 'static,
+{
+    let Some(timeout) = timeout else {
+        return crate::runtime::block_on(fut);
+    };
+
+    let rt = crate::runtime::get()?;
+    // Spawned rather than blocked on, as in `start`, so the timer fires even while a statement
+    // blocks the thread it runs on.
+    let mut task = rt.spawn(fut);
+    rt.block_on(async {
+        match tokio::time::timeout(timeout, &mut task).await {
+            Ok(res) => res.unwrap_or_else(|_| {
+                Err(mlua::Error::RuntimeError("async task panicked".to_string()))
+            }),
+            Err(_) => {
+                task.abort();
+                Err(LuaSqlError::timeout().into())
+            }
+        }
+    })
+}
+
+/// Hands `future` to the caller of an async method: through `cb` if there is one, by yielding
+/// the calling coroutine if there is one, or as the return value otherwise.
+pub(crate) fn deliver(lua: &Lua, future: LuaFuture, cb: Option<OwnedFunction>) -> Pending<'_> {
+    let thread = match cb {
+        Some(cb) => future.listen_callback(lua, cb).map(|_| None),
+        None => running_coroutine(lua),
+    };
+    match thread {
+        Ok(Some(thread)) => Pending::awaiting(lua, future, thread.into_owned()),
+        Ok(None) => Pending::ready(future.into_lua(lua)),
+        Err(err) => Pending::ready(Err(err)),
+    }
+}
+
+/// Runs `fut` on the shared runtime and returns a future settled with its result.
+pub(crate) fn start<T, F>(lua: &Lua, timeout: Option<Duration>, fut: F) -> mlua::Result<LuaFuture>
+where
+    T: for<'a> IntoLuaMulti<'a> + Send + 'static,
+    F: Future<Output = mlua::Result<T>> + Send + 'static,
+{
+    let future = LuaFuture::default();
+    let slot: Slot<T> = Arc::new(Mutex::new(None));
+
+    // Converting the result needs the Lua state, which the libuv callback doesn't get, so the
+    // callback goes through a Lua function.
+    let settle = lua
+        .create_function({
+            let slot = Arc::clone(&slot);
+            let future = future.clone();
+            move |lua, ()| {
+                let Some(rv) = slot.lock().unwrap_or_else(PoisonError::into_inner).take() else {
+                    return Err(mlua::Error::RuntimeError("data not set".to_string()));
+                };
+                let rv = rv
+                    .and_then(|rv| rv.into_lua_multi(lua))
+                    .map(|rv| rv.into_iter().next().unwrap_or(mlua::Value::Nil));
+                future.settle(lua, rv)
+            }
+        })?
+        .into_owned();
+
+    let handle = nvim_oxi::libuv::AsyncHandle::new(move || {
+        if let Err(err) = settle.call::<_, ()>(()) {
+            report(err);
+        }
+        mlua::Result::Ok(())
+    })
+    .into_lua_err()?;
+
+    let rt = crate::runtime::get()?;
+    let mut task = rt.spawn(fut);
+    future.set_abort(task.abort_handle());
+
+    rt.spawn(async move {
+        // The timeout is kept here rather than in the task, where a statement blocking its
+        // worker thread would keep the timer from ever being polled.
+        let res = match timeout {
+            None => task.await,
+            Some(timeout) => match tokio::time::timeout(timeout, &mut task).await {
+                Ok(res) => res,
+                Err(_) => {
+                    // libsql cannot interrupt a statement, so one that is already executing on
+                    // a local connection still runs to completion. Its result is dropped.
+                    task.abort();
+                    Ok(Err(LuaSqlError::timeout().into()))
+                }
+            },
+        };
+
+        // Every failure (including the task panicking) must be stored before `send` so the
+        // future always settles.
+        let res = res.unwrap_or_else(|err| {
+            Err(if err.is_cancelled() {
+                LuaSqlError::cancelled().into()
+            } else {
+                mlua::Error::RuntimeError("async task panicked".to_string())
+            })
+        });
+
+        slot.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(res);
+        handle.send()
+    });
+
+    Ok(future)
+}
+
+/// Returns the coroutine the current call is running in, or `None` on the main thread.
+pub(crate) fn running_coroutine(lua: &Lua) -> mlua::Result<Option<mlua::Thread<'_>>> {
+    let running: mlua::Function = lua
+        .globals()
+        .get::<_, mlua::Table>("coroutine")?
+        .get("running")?;
+
+    // LuaJIT returns nil on the main thread, or `(main, true)` when built with 5.2 compatibility.
+    let (thread, is_main): (Option<mlua::Thread>, Option<bool>) = running.call(())?;
+
+    Ok(thread.filter(|_| is_main != Some(true)))
+}
+
+/// Errors raised while delivering a result have no Lua caller left to propagate to, so they are
+/// rethrown from `vim.schedule`, where Neovim reports them.
+pub(crate) fn report(err: mlua::Error) {
+    nvim_oxi::schedule(move |_| Err(err.into()));
+}
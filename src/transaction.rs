@@ -0,0 +1,176 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+use libsql_nvim_derive::{lua_methods, FromLuaSerde};
+use mlua::serde::LuaSerdeExt;
+use mlua::{OwnedFunction, UserData};
+use tokio::sync::RwLock;
+
+use crate::conn::SharedConnection;
+use crate::error::SqlResultExt;
+use crate::json::Decode;
+use crate::task::Timeout;
+use crate::types::TypeOptions;
+use crate::{conn::ParamsList, prelude::*, rows::LuaRows};
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub enum LuaTransactionBehavior {
+    #[serde(rename = "deferred")]
+    Deferred,
+    #[serde(rename = "immediate")]
+    Immediate,
+    #[serde(rename = "exclusive")]
+    Exclusive,
+}
+
+impl From<LuaTransactionBehavior> for libsql::TransactionBehavior {
+    fn from(behavior: LuaTransactionBehavior) -> Self {
+        match behavior {
+            LuaTransactionBehavior::Deferred => libsql::TransactionBehavior::Deferred,
+            LuaTransactionBehavior::Immediate => libsql::TransactionBehavior::Immediate,
+            LuaTransactionBehavior::Exclusive => libsql::TransactionBehavior::Exclusive,
+        }
+    }
+}
+
+/// The rollback of a transaction dropped without being committed, shared through its connection's
+/// `ConnectionState`. Calls on the connection wait for it, so they don't run inside the
+/// transaction.
+#[derive(Clone, Default)]
+pub struct PendingRollback(Arc<Mutex<Option<Arc<tokio::sync::Mutex<()>>>>>);
+
+impl PendingRollback {
+    /// Rolls `tx` back on the shared runtime. `wait` blocks until it is done.
+    pub(crate) fn start(&self, tx: libsql::Transaction) {
+        let Ok(rt) = crate::runtime::get() else {
+            return;
+        };
+
+        // Locked before the rollback is spawned, so a call made right after can't get ahead of it.
+        let done = Arc::new(tokio::sync::Mutex::new(()));
+        let Ok(guard) = Arc::clone(&done).try_lock_owned() else {
+            return;
+        };
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(done);
+
+        rt.spawn(async move {
+            let _ = tx.rollback().await;
+            drop(guard);
+        });
+    }
+
+    /// Waits for the last rollback started with `start` to finish.
+    pub async fn wait(&self) {
+        let pending = self
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        if let Some(done) = pending {
+            let _ = done.lock().await;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LuaTransaction(Arc<LuaTransactionInner>);
+
+struct LuaTransactionInner {
+    /// `None` once the transaction has been committed or rolled back.
+    tx: RwLock<Option<libsql::Transaction>>,
+    /// The connection the transaction was started on. Calls take its lock like calls on the
+    /// connection do.
+    conn: SharedConnection,
+    /// The timeout of the connection the transaction was started on.
+    timeout: Timeout,
+    decode: Arc<Decode>,
+    types: TypeOptions,
+}
+
+impl Drop for LuaTransactionInner {
+    fn drop(&mut self) {
+        let Some(tx) = self.tx.get_mut().take() else {
+            return;
+        };
+
+        // The userdata was garbage-collected without being committed. Lua's GC runs outside of
+        // any Tokio context, where libsql skips its own rollback for remote databases, so roll
+        // back explicitly on the shared runtime.
+        self.conn.roll_back(tx);
+    }
+}
+
+fn finished() -> mlua::Error {
+    mlua::Error::RuntimeError("transaction has already been committed or rolled back".to_string())
+}
+
+#[lua_methods]
+impl LuaTransaction {
+    pub fn new(
+        tx: libsql::Transaction,
+        conn: SharedConnection,
+        timeout: Timeout,
+        decode: Arc<Decode>,
+        types: TypeOptions,
+    ) -> Self {
+        LuaTransaction(Arc::new(LuaTransactionInner {
+            tx: RwLock::new(Some(tx)),
+            conn,
+            timeout,
+            decode,
+            types,
+        }))
+    }
+
+    #[lua_method(async, sync, timeout = self.0.timeout.get())]
+    pub async fn execute(
+        &self,
+        (sql, params, cb): (String, ParamsList, Option<OwnedFunction>),
+    ) -> mlua::Result<u64> {
+        let _conn = self.0.conn.lock().await;
+        let tx = self.0.tx.read().await;
+        let tx = tx.as_ref().ok_or_else(finished)?;
+
+        crate::conn::execute(tx, &sql, params).await
+    }
+
+    #[lua_method(async, sync, timeout = self.0.timeout.get())]
+    pub async fn query(
+        &self,
+        (sql, params, cb): (String, ParamsList, Option<OwnedFunction>),
+    ) -> mlua::Result<LuaRows> {
+        let _conn = self.0.conn.lock().await;
+        let tx = self.0.tx.read().await;
+        let tx = tx.as_ref().ok_or_else(finished)?;
+
+        let rows = LuaRows::query(tx, &sql, params, self.0.types).await?;
+
+        mlua::Result::Ok(rows.with_decode(Arc::clone(&self.0.decode)))
+    }
+
+    #[lua_method(async, sync, timeout = self.0.timeout.get())]
+    pub async fn commit(&self, cb: Option<OwnedFunction>) -> mlua::Result<()> {
+        let _conn = self.0.conn.lock().await;
+        let tx = self.0.tx.write().await.take().ok_or_else(finished)?;
+
+        tx.commit().await.into_sql_err()
+    }
+
+    #[lua_method(async, sync, timeout = self.0.timeout.get())]
+    pub async fn rollback(&self, cb: Option<OwnedFunction>) -> mlua::Result<()> {
+        let _conn = self.0.conn.lock().await;
+        let tx = self.0.tx.write().await.take().ok_or_else(finished)?;
+
+        tx.rollback().await.into_sql_err()
+    }
+}
+
+impl UserData for LuaTransaction {
+    fn add_fields<'lua, F: mlua::prelude::LuaUserDataFields<'lua, Self>>(_fields: &mut F) {}
+
+    fn add_methods<'lua, M: mlua::prelude::LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        Self::add_lua_methods(methods);
+    }
+}
@@ -0,0 +1,203 @@
+//! `TypeOptions`, how SQL values are converted to Lua values.
+//!
+//! Set for a database in its config and for a connection with `conn:with_types`. The defaults
+//! keep the original conversions: `NULL` to `nil`, and every integer to a number.
+
+use libsql_nvim_derive::FromLuaSerde;
+use mlua::serde::LuaSerdeExt;
+use mlua::Value;
+
+use crate::datetime::{DateMode, Timestamp};
+use crate::prelude::*;
+
+/// The largest integer a Lua number holds exactly.
+const MAX_SAFE_INTEGER: u64 = 1 << 53;
+
+/// What a null becomes.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub enum NullMode {
+    /// `nil`, which leaves no key in a table keyed by column name, and a hole in a list.
+    #[serde(rename = "nil")]
+    Nil,
+    /// `vim.NIL`, so the value keeps its place.
+    #[serde(rename = "null")]
+    Null,
+}
+
+/// What integers that a Lua number can't hold exactly become.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub enum BigIntegers {
+    /// The nearest number.
+    #[serde(rename = "number")]
+    Number,
+    /// Their decimal digits.
+    #[serde(rename = "string")]
+    String,
+}
+
+/// What a column's declared type says about its values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Declared {
+    #[default]
+    Other,
+    /// `BOOLEAN` or `BOOL`.
+    Boolean,
+    /// `DATE`, `DATETIME` or `TIMESTAMP`.
+    Date,
+}
+
+/// LuaJIT has a single number type, so `REAL` and `INTEGER` values both become numbers, and a
+/// number with an integral value binds as an `INTEGER`. `row:column_type` tells them apart.
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, FromLuaSerde)]
+pub struct TypeOptions {
+    /// What SQL `NULL` becomes. Defaults to `nil`.
+    null: Option<NullMode>,
+    /// Whether integers in columns declared `BOOLEAN` become booleans. Defaults to false.
+    booleans: Option<bool>,
+    /// What integers beyond ±2^53 become. Defaults to numbers.
+    big_integers: Option<BigIntegers>,
+    /// What values in columns declared `DATE`, `DATETIME` or `TIMESTAMP` become. Defaults to
+    /// leaving them as stored.
+    dates: Option<DateMode>,
+}
+
+impl TypeOptions {
+    /// These options, with those set in `overrides` replaced.
+    pub fn merge(self, overrides: TypeOptions) -> TypeOptions {
+        TypeOptions {
+            null: overrides.null.or(self.null),
+            booleans: overrides.booleans.or(self.booleans),
+            big_integers: overrides.big_integers.or(self.big_integers),
+            dates: overrides.dates.or(self.dates),
+        }
+    }
+
+    fn booleans(&self) -> bool {
+        self.booleans.unwrap_or(false)
+    }
+
+    /// Whether queries need the declared types of their columns.
+    pub fn uses_declared_types(&self) -> bool {
+        self.booleans() || self.dates.is_some()
+    }
+
+    /// Converts `value`, from a column with the `declared` type.
+    pub fn to_lua(
+        self,
+        lua: &Lua,
+        value: libsql::Value,
+        declared: Declared,
+    ) -> mlua::Result<Value<'_>> {
+        if let (Declared::Date, Some(mode)) = (declared, self.dates) {
+            // Values that aren't dates are returned as they are.
+            if let Some(timestamp) = Timestamp::from_sql(&value) {
+                return timestamp.to_lua(lua, mode);
+            }
+        }
+
+        let boolean = declared == Declared::Boolean;
+        match value {
+            libsql::Value::Null => match self.null.unwrap_or(NullMode::Nil) {
+                NullMode::Nil => Ok(Value::Nil),
+                NullMode::Null => vim_nil(lua),
+            },
+            libsql::Value::Integer(i) if boolean && self.booleans() => Ok(Value::Boolean(i != 0)),
+            libsql::Value::Integer(i)
+                if i.unsigned_abs() > MAX_SAFE_INTEGER
+                    && matches!(self.big_integers, Some(BigIntegers::String)) =>
+            {
+                i.to_string().into_lua(lua)
+            }
+            libsql::Value::Integer(i) => i.into_lua(lua),
+            libsql::Value::Real(f) => f.into_lua(lua),
+            libsql::Value::Text(str) => str.into_lua(lua),
+            libsql::Value::Blob(bytes) => lua.create_string(bytes).map(Value::String),
+        }
+    }
+}
+
+/// `vim.NIL`, kept in the registry once read from the globals.
+struct VimNil(mlua::RegistryKey);
+
+/// Neovim's `vim.NIL`, a userdata of its own. Outside of Neovim, mlua's null stands in for it.
+pub fn vim_nil(lua: &Lua) -> mlua::Result<Value<'_>> {
+    if let Some(nil) = lua.app_data_ref::<VimNil>() {
+        return lua.registry_value(&nil.0);
+    }
+
+    let nil = match lua.globals().get::<_, Option<mlua::Table>>("vim")? {
+        Some(vim) => vim.get("NIL")?,
+        None => Value::Nil,
+    };
+    let nil = if nil.is_nil() { lua.null() } else { nil };
+    lua.set_app_data(VimNil(lua.create_registry_value(nil.clone())?));
+
+    Ok(nil)
+}
+
+/// Whether `value` is `vim.NIL`, or mlua's null.
+pub fn is_null(lua: &Lua, value: &Value) -> mlua::Result<bool> {
+    Ok(match value {
+        Value::LightUserData(ud) => ud.0.is_null() || *value == vim_nil(lua)?,
+        Value::UserData(_) => *value == vim_nil(lua)?,
+        _ => false,
+    })
+}
+
+/// What the declared type of each column of `stmt` says about its values.
+pub fn declared_types(stmt: &libsql::Statement) -> Vec<Declared> {
+    stmt.columns()
+        .iter()
+        .map(|column| match column.decl_type() {
+            Some(decl_type)
+                if ["boolean", "bool"]
+                    .iter()
+                    .any(|t| decl_type.eq_ignore_ascii_case(t)) =>
+            {
+                Declared::Boolean
+            }
+            Some(decl_type)
+                if ["date", "datetime", "timestamp"]
+                    .iter()
+                    .any(|t| decl_type.eq_ignore_ascii_case(t)) =>
+            {
+                Declared::Date
+            }
+            _ => Declared::Other,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use luajit2_sys as _;
+
+    use super::*;
+    use crate::db::LuaDatabase;
+
+    #[test]
+    fn vim_nil_round_trips_as_null() {
+        let lua = Lua::new();
+        // Neovim's `vim.NIL` is a full userdata.
+        lua.load("vim = { NIL = newproxy(false) }").exec().unwrap();
+
+        let config = serde_json::json!({ "kind": "memory", "types": { "null": "null" } });
+        let db = LuaDatabase::create_sync(serde_json::from_value(config).unwrap()).unwrap();
+        lua.globals()
+            .set("conn", db.connect_sync().unwrap())
+            .unwrap();
+
+        let (null, json): (bool, String) = lua
+            .load(
+                r#"
+                local rows = conn:query_sync("SELECT ? AS a, ? AS b", { vim.NIL, { vim.NIL } })
+                local row = rows:next_sync()
+                return row.a == vim.NIL, row.b
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert!(null);
+        assert_eq!(json, "[null]");
+    }
+}
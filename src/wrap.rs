@@ -96,7 +96,7 @@ where
     MR: Future<Output = mlua::Result<R>> + 'a,
     Self: Sized + Fn(&'a T, &'lua Lua, A) -> MR + 'static,
 {
-    fn wrap_async(self) -> impl Fn(&'lua Lua, &'a T, A) -> MR;
+    fn wrap_async(self) -> impl Fn(&'lua Lua, &'a T, A) -> MR + 'static;
 }
 impl<'lua, 'a, F, T, A, R, MR> AsyncMethodWithLua<'lua, 'a, T, A, R, MR> for F
 where
@@ -107,7 +107,7 @@ where
     MR: Future<Output = mlua::Result<R>> + 'a,
     Self: Sized + Fn(&'a T, &'lua Lua, A) -> MR + 'static,
 {
-    fn wrap_async(self) -> impl Fn(&'lua Lua, &'a T, A) -> MR {
+    fn wrap_async(self) -> impl Fn(&'lua Lua, &'a T, A) -> MR + 'static {
         move |lua, this, args| self(this, lua, args)
     }
 }
@@ -148,7 +148,7 @@ where
     MR: Future<Output = mlua::Result<R>> + 'a,
     Self: Sized + Fn(&'a mut T, &'lua Lua, A) -> MR + 'static,
 {
-    fn wrap_async_mut(self) -> impl Fn(&'lua Lua, &'a mut T, A) -> MR;
+    fn wrap_async_mut(self) -> impl Fn(&'lua Lua, &'a mut T, A) -> MR + 'static;
 }
 impl<'lua, 'a, F, T, A, R, MR> AsyncMethodMutWithLua<'lua, 'a, T, A, R, MR> for F
 where
@@ -159,7 +159,7 @@ where
     MR: Future<Output = mlua::Result<R>> + 'a,
     Self: Sized + Fn(&'a mut T, &'lua Lua, A) -> MR + 'static,
 {
-    fn wrap_async_mut(self) -> impl Fn(&'lua Lua, &'a mut T, A) -> MR {
+    fn wrap_async_mut(self) -> impl Fn(&'lua Lua, &'a mut T, A) -> MR + 'static {
         move |lua, this, args| self(this, lua, args)
     }
 }
@@ -174,7 +174,7 @@ where
     MR: Future<Output = mlua::Result<R>> + 'static,
     Self: Sized + Fn(&'a mut T, A) -> MR + 'static,
 {
-    fn wrap_async_mut(self) -> impl Fn(&'lua Lua, &'a mut T, A) -> MR;
+    fn wrap_async_mut(self) -> impl Fn(&'lua Lua, &'a mut T, A) -> MR + 'static;
 }
 impl<'lua, 'a, F, T, A, R, MR> AsyncMethodMutNoLua<'lua, 'a, T, A, R, MR> for F
 where
@@ -185,7 +185,7 @@ where
     MR: Future<Output = mlua::Result<R>> + 'static,
     Self: Sized + Fn(&'a mut T, A) -> MR + 'static,
 {
-    fn wrap_async_mut(self) -> impl Fn(&'lua Lua, &'a mut T, A) -> MR {
+    fn wrap_async_mut(self) -> impl Fn(&'lua Lua, &'a mut T, A) -> MR + 'static {
         move |_lua, this, args| self(this, args)
     }
 }